@@ -1,5 +1,8 @@
 use clap::Parser;
-use std::io::{self, Read};
+use anyhow::{bail, Result};
+use std::io::{self, IsTerminal, Read};
+
+use crate::util;
 
 /// Efficiently run Google Ads GAQL query across one or more child accounts linked to MCC.
 ///
@@ -12,6 +15,10 @@ pub struct Cli {
     #[clap(short, long)]
     pub profile: Option<String>,
 
+    /// Config file to use instead of the default in the platform config dir. Also settable via MCC_GAQL_CONFIG
+    #[clap(long)]
+    pub config: Option<String>,
+
     /// Apply query to a single CustomerID. Or use with `--all-linked-child-accounts` to query all child accounts.
     #[clap(short, long)]
     pub customer_id: Option<String>,
@@ -20,17 +27,158 @@ pub struct Cli {
     #[clap(short = 'q', long)]
     pub stored_query: Option<String>,
 
+    /// Query parameter as name=value, substituted for `{{name}}` placeholders.
+    /// Inside `IN ({{name}})`, comma-separated values expand to a quoted list
+    #[clap(long, multiple_occurrences(true))]
+    pub param: Vec<String>,
+
+    /// Name of a `--param` whose comma-separated list is split into chunks of `--chunk-size` distinct values,
+    /// running the query once per chunk for each account and merging results, for IN-lists too long for one query
+    #[clap(long)]
+    pub chunk_param: Option<String>,
+
+    /// Max values per chunk for `--chunk-param`
+    #[clap(long, default_value_t = 1000)]
+    pub chunk_size: usize,
+
+    /// Query change_event for account changes in the `--since`/`--until` window (default: last 7 days)
+    #[clap(long)]
+    pub changes: bool,
+
+    /// Max change records per account for `--changes`, at most 10000
+    #[clap(long, default_value_t = 1000)]
+    pub changes_limit: usize,
+
+    /// Start date of the query window: YYYY-MM-DD, today, yesterday, or relative like 30d, 2w, 3m, 1y ago.
+    /// Filters on `--date-field`, or change date with `--changes`
+    #[clap(long)]
+    pub since: Option<String>,
+
+    /// End date (inclusive) of the query window, in the same formats as `--since`
+    #[clap(long)]
+    pub until: Option<String>,
+
+    /// Filterable DATE or DATETIME field that `--since`, `--until` and `--since-last-run` filter on, e.g. `campaign.start_date`
+    #[clap(long, default_value = "segments.date")]
+    pub date_field: String,
+
+    /// Only fetch data since the last successful run of this query and profile, by filtering on `--date-field`
+    #[clap(long)]
+    pub since_last_run: bool,
+
+    /// Start date used by `--since-last-run` when no previous run is recorded, in the same formats as `--since`
+    #[clap(long)]
+    pub initial_since: Option<String>,
+
     /// Google Ads GAQL query to run
     pub gaql_query: Option<String>,
 
+    /// Print the profile's config after merging the config file and MCC_GAQL_* env vars, as toml or json, then exit.
+    /// Config holds no credentials; the OAuth client secret and token cache are separate files
+    #[clap(long, possible_values = &["toml", "json"])]
+    pub dump_resolved_config: Option<String>,
+
     /// List all child accounts under MCC
     #[clap(short, long)]
     pub list_child_accounts: bool,
 
-    /// Query GoogleAdsFieldService to retrieve available fields
+    /// Run a trivial probe query and report its API unit consumption, to check API access before a big run
+    #[clap(long)]
+    pub check_quota: bool,
+
+    /// List valid values of an ENUM field, e.g. `campaign.status`, for use in WHERE clauses
+    #[clap(long)]
+    pub enum_values: Option<String>,
+
+    /// Query GoogleAdsFieldService to retrieve available fields and their capabilities.
+    /// Only selected attributes are populated, e.g. `SELECT name, category, data_type, selectable, filterable, sortable WHERE name LIKE 'campaign.%'`
     #[clap(short, long)]
     pub field_service: bool,
 
+    /// What stdin contains: the GAQL query (default, read only when no query is given), CustomerIDs (same as `--accounts @-`), or none
+    #[clap(long, default_value = "query", possible_values = &["query", "accounts", "none"])]
+    pub stdin: String,
+
+    /// Accounts to query: comma-separated CustomerIDs, a file with one CustomerID per line, or `@-` to read CustomerIDs from stdin
+    #[clap(long)]
+    pub accounts: Option<String>,
+
+    /// Query child accounts of `--customer-id`, or the profile MCC, that match an expression on their listing,
+    /// e.g. "currency_code == 'USD' && time_zone != 'UTC'". Columns: id, level, currency_code, time_zone, descriptive_name
+    #[clap(long)]
+    pub account_filter: Option<String>,
+
+    /// TOML file of `"<customer_id>" = "<query>"` entries, giving accounts their own query in place of the default.
+    /// Mapped queries get the same `--param` values and date window, and must select the same fields as the default query
+    #[clap(long)]
+    pub query_map: Option<String>,
+
+    /// CSV file of `mcc_id,customer_id` pairs to query, each using its own MCC as login-customer-id,
+    /// for accounts spread across manager accounts. The profile's credentials need access to every MCC
+    #[clap(long)]
+    pub account_pairs: Option<String>,
+
+    /// Probe each account with a LIMIT 1 run of the query first, and skip accounts that fail it
+    #[clap(long)]
+    pub probe: bool,
+
+    /// Only query the first N resolved accounts, e.g. to smoke-test a query on a sample
+    #[clap(long)]
+    pub limit_accounts: Option<usize>,
+
+    /// Randomize resolved account order, so `--limit-accounts` picks a random sample
+    #[clap(long)]
+    pub shuffle_accounts: bool,
+
+    /// Seed for `--shuffle-accounts`, to reproduce a sample
+    #[clap(long, requires = "shuffle-accounts")]
+    pub shuffle_seed: Option<u64>,
+
+    /// Stop reading an account's results after N rows, as a memory safety valve for runaway accounts.
+    /// Truncated accounts are reported in the run summary
+    #[clap(long)]
+    pub per_account_max_rows: Option<usize>,
+
+    /// Retry accounts that return no rows before accepting the empty result,
+    /// for eventually-consistent resources queried right after changes
+    #[clap(long)]
+    pub retry_on_empty: bool,
+
+    /// Extra attempts per account for `--retry-on-empty`
+    #[clap(long, default_value_t = 3)]
+    pub retry_empty_attempts: usize,
+
+    /// Seconds to wait between `--retry-on-empty` attempts
+    #[clap(long, default_value_t = 5)]
+    pub retry_empty_delay: u64,
+
+    /// Wall-clock budget in seconds for querying all accounts. When exceeded, unfinished accounts are
+    /// cut off, partial results are written, and the run exits with code 124
+    #[clap(long)]
+    pub timeout_overall: Option<u64>,
+
+    /// Dispatch accounts by their row counts from previous runs of the query, recorded on every run using this option.
+    /// Accounts without history go last. `none` only records row counts
+    #[clap(long, possible_values = &["largest-first", "smallest-first", "none"])]
+    pub order_accounts_by_size: Option<String>,
+
+    /// Cache merged results of complete runs, and reuse them for runs with the same query fingerprint and
+    /// grouping options within `--results-ttl`, without querying the API
+    #[clap(long)]
+    pub cache_results: bool,
+
+    /// Seconds cached results stay fresh for `--cache-results`
+    #[clap(long, default_value_t = 300)]
+    pub results_ttl: u64,
+
+    /// With `--cache-results`, ignore cached results and query the API, refreshing the cache
+    #[clap(long, requires = "cache-results")]
+    pub no_cache: bool,
+
+    /// Number of fields per GoogleAdsFieldService response page. Smaller pages reduce memory per response but need more round trips
+    #[clap(long, default_value_t = 10000)]
+    pub fields_page_size: i32,
+
     /// Force query to run across all linked child accounts (some may not be accessible)
     #[clap(short, long)]
     pub all_linked_child_accounts: bool,
@@ -39,23 +187,278 @@ pub struct Cli {
     #[clap(long)]
     pub keep_going: bool,
 
+    /// Abort on the first account error, cancelling queries for remaining accounts, e.g. to check a query works everywhere in CI
+    #[clap(long, conflicts_with = "keep-going")]
+    pub fail_fast: bool,
+
     /// Group by columns
     #[clap(long, multiple_occurrences(true))]
     pub groupby: Vec<String>,
 
+    /// Add an `account_count` column with the number of distinct accounts contributing to each `--groupby` group
+    #[clap(long, requires = "groupby")]
+    pub count_accounts_per_group: bool,
+
+    /// Aggregate a metric with `--groupby` as col=fn, where fn is sum (default), min, max,
+    /// or wmean:<weight col> for a weighted mean, e.g. `metrics.ctr=wmean:metrics.impressions`
+    #[clap(long, multiple_occurrences(true))]
+    pub agg: Vec<String>,
+
     /// Sort by columns
     #[clap(long, multiple_occurrences(true))]
     pub sortby: Vec<String>,
 
+    /// Print the execution plan for the GAQL query (accounts, final query, limits, output) without running it
+    #[clap(long)]
+    pub explain_plan: bool,
+
+    /// Rebuild `<resource>.resource_name` columns from selected `<resource>.id` columns, e.g. for joins
+    #[clap(long)]
+    pub reconstruct_resource_names: bool,
+
+    /// Prepend a column with the customer_id each row was fetched from
+    #[clap(long)]
+    pub add_customer_id: bool,
+
+    /// Column name used by `--add-customer-id`
+    #[clap(long, default_value = "customer_id")]
+    pub customer_id_column: String,
+
+    /// Add a `customer_name` column from a local `id,name` CSV file, an offline alternative to looking up account names.
+    /// Accounts not in the file get a null name
+    #[clap(long)]
+    pub account_labels: Option<String>,
+
+    /// Append a UTC timestamp column, constant per run, recording when results were fetched
+    #[clap(long)]
+    pub add_fetched_at: bool,
+
+    /// Column name used by `--add-fetched-at`
+    #[clap(long, default_value = "fetched_at")]
+    pub fetched_at_column: String,
+
+    /// Print a timing breakdown of each pipeline stage when done
+    #[clap(long)]
+    pub benchmark: bool,
+
+    /// Server-computed summary row of metric totals: none, with-results (appended per account, dimensions empty; not with --groupby or --compare-accounts), or only
+    #[clap(long, default_value = "none", possible_values = &["none", "with-results", "only"])]
+    pub summary_mode: String,
+
+    /// Drop rows where every metric is zero or null, e.g. to only show campaigns with activity
+    #[clap(long)]
+    pub prune_zero_rows: bool,
+
+    /// Left join a local CSV or Parquet file onto results, e.g. to add an account region or project code
+    #[clap(long)]
+    pub merge_with: Option<String>,
+
+    /// Key column to join `--merge-with` on. Repeat for multiple keys
+    #[clap(long, multiple_occurrences(true), requires = "merge-with")]
+    pub on: Vec<String>,
+
+    /// Order columns as resource attributes, then segments, then metrics, alphabetically within each, regardless of SELECT order.
+    /// Applied before `--expr`, so an `--expr` select step sets the final order
+    #[clap(long)]
+    pub smart_column_order: bool,
+
+    /// Post-processing step applied to merged results, in order: `filter <expr>`,
+    /// `with_column <name> = <expr>` or `select <col>, ...`. Runs after `--smart-column-order`,
+    /// before `--cast` and `--humanize-metrics`. See README for expression syntax and post-processing order
+    #[clap(long, multiple_occurrences(true))]
+    pub expr: Vec<String>,
+
+    /// Override a result column type as col:type, where type is one of str, i64, f64, bool.
+    /// Applied after `--expr`, before `--humanize-metrics`, so use the raw column names
+    #[clap(long, multiple_occurrences(true))]
+    pub cast: Vec<String>,
+
+    /// Correlation ID for this run, shown in logs and the audit log. Generated when not given
+    #[clap(long)]
+    pub run_id: Option<String>,
+
+    /// Append a JSONL record of each GAQL run (who, when, query, accounts, rows, API units) to this file
+    #[clap(long)]
+    pub audit_log: Option<String>,
+
+    /// Save raw API responses per account to this directory, for debugging result parsing
+    #[clap(long)]
+    pub trace: Option<String>,
+
+    /// Write one output file per distinct value of this column, named `<output stem>_<value>.csv`
+    #[clap(long)]
+    pub split_by: Option<String>,
+
+    /// List whether each target account accepts the query, by probing it with LIMIT 1, then exit.
+    /// Outputs customer_id, accessible and error columns
+    #[clap(long)]
+    pub list_accessible: bool,
+
+    /// Validate each SELECT field of the query on its own with the live API, against the first target account,
+    /// and print which fields pass or fail, then exit. Narrows down the field behind a failing query
+    #[clap(long)]
+    pub probe_fields: bool,
+
+    /// Print a fingerprint of the effective query and target accounts, then exit.
+    /// Runs with the same query and accounts share a fingerprint, which is also recorded in audit logs and schema metadata
+    #[clap(long)]
+    pub print_fingerprint: bool,
+
+    /// Validate the query with the live API against one account, without fetching data, then exit
+    #[clap(long)]
+    pub validate_query: bool,
+
+    /// Print result column names and types by running the query with LIMIT 1 against one account, then exit
+    #[clap(long)]
+    pub schema_preview: bool,
+
+    /// Convert metrics to human-friendly units: micros to currency units, fractional rates to percentages.
+    /// Applied after `--expr` and `--cast`, before `--compare-accounts`, `--histogram` and `--rename-map`
+    #[clap(long)]
+    pub humanize_metrics: bool,
+
+    /// Format metrics for readability when printing a table to stdout: thousand separators, micros as currency,
+    /// rates as percentages. Files and sheets keep raw values
+    #[clap(long)]
+    pub humanize: bool,
+
+    /// Compare accounts side by side: pivot this metric into one column per customer_id, with other columns as rows.
+    /// Applied after `--humanize-metrics`, before `--histogram` and `--rename-map`
+    #[clap(long)]
+    pub compare_accounts: Option<String>,
+
+    /// Remove this resource's prefix from output column names, e.g. `campaign` turns `campaign.name` into `name`,
+    /// leaving `metrics.` and `segments.` columns qualified. Applied last. Repeat for multiple resources
+    #[clap(long, multiple_occurrences(true))]
+    pub strip_prefix: Vec<String>,
+
+    /// File mapping output column names to report labels, applied after all other post-processing except `--strip-prefix`:
+    /// CSV lines of from,to, or a `.toml` file of `"from" = "to"` entries
+    #[clap(long)]
+    pub rename_map: Option<String>,
+
+    /// Start CSV output files with `#` comment lines giving the API version and each column's type,
+    /// so archived results describe their own schema
+    #[clap(long)]
+    pub embed_schema_metadata: bool,
+
+    /// Output a histogram of this numeric column (row count per equal-width bucket) instead of rows.
+    /// Applied after `--humanize-metrics` and `--compare-accounts`, so name the column as they leave it
+    #[clap(long)]
+    pub histogram: Option<String>,
+
+    /// Number of buckets for `--histogram`
+    #[clap(long, default_value_t = 10)]
+    pub buckets: usize,
+
+    /// CSV quoting: necessary (default), always, non_numeric, or never. `never` can break parsing of values containing the separator
+    #[clap(long, default_value = "necessary", possible_values = &["necessary", "always", "non_numeric", "never"])]
+    pub quote_style: String,
+
+    /// Encoding of CSV output files, e.g. windows-1252 for legacy ingestion pipelines. Does not apply to stdout
+    #[clap(long, default_value = "utf-8")]
+    pub encoding: String,
+
+    /// Replacement for characters that `--encoding` can't represent
+    #[clap(long, default_value = "?")]
+    pub encoding_placeholder: String,
+
+    /// Skip confirmation prompts, e.g. before printing a very large table
+    #[clap(short, long)]
+    pub yes: bool,
+
     /// GAQL output filename
     #[clap(short, long)]
     pub output: Option<String>,
+
+    /// Write GAQL results to this Google Sheets spreadsheet ID instead of stdout or file
+    #[cfg(feature = "sheets")]
+    #[clap(long)]
+    pub sheet: Option<String>,
+
+    /// Sheet range in A1 notation used by `--sheet`
+    #[cfg(feature = "sheets")]
+    #[clap(long, default_value = "Sheet1")]
+    pub range: String,
+
+    /// Append rows below existing values instead of overwriting the `--sheet` range
+    #[cfg(feature = "sheets")]
+    #[clap(long)]
+    pub sheet_append: bool,
+}
+
+impl Cli {
+    /// Check for flag combinations that can't work together, before any config, file or API access
+    pub fn validate(&self) -> Result<()> {
+        if self.changes && (self.gaql_query.is_some() || self.stored_query.is_some()) {
+            bail!("--changes builds its own change_event query and cannot be combined with a GAQL query");
+        }
+        if !self.changes && self.since_last_run && (self.since.is_some() || self.until.is_some()) {
+            bail!("--since/--until cannot be combined with --since-last-run");
+        }
+
+        if self.chunk_param.is_some() {
+            // each chunk would return its own summary row, totalling only part of the account
+            if self.summary_mode != "none" {
+                bail!("--summary-mode cannot be combined with --chunk-param");
+            }
+            if self.query_map.is_some() {
+                bail!("--query-map cannot be combined with --chunk-param");
+            }
+        }
+
+        // summary rows carry no marker, so grouping or pruning would fold their totals in with regular rows
+        if self.summary_mode == "with-results"
+            && (!self.groupby.is_empty()
+                || self.compare_accounts.is_some()
+                || !self.agg.is_empty()
+                || self.count_accounts_per_group
+                || self.prune_zero_rows)
+        {
+            bail!("--summary-mode with-results cannot be combined with --groupby, --agg, --count-accounts-per-group, --compare-accounts or --prune-zero-rows");
+        }
+
+        if !self.agg.is_empty() && self.groupby.is_empty() {
+            bail!("--agg requires --groupby");
+        }
+        if self.buckets == 0 {
+            bail!("--buckets must be at least 1");
+        }
+        if self.split_by.is_some() && self.output.is_none() {
+            bail!("--split-by requires --output to name the split files");
+        }
+
+        Ok(())
+    }
 }
 
 pub fn parse() -> Cli {
     let mut cli = Cli::parse();
 
-    if cli.stored_query.is_none() && cli.gaql_query.is_none() && !cli.list_child_accounts {
+    if cli.stdin == "accounts" {
+        match cli.accounts.as_deref() {
+            None | Some(util::ACCOUNTS_FROM_STDIN) => {
+                cli.accounts = Some(util::ACCOUNTS_FROM_STDIN.to_owned());
+            }
+            Some(spec) => util::fail(format!("--stdin accounts conflicts with --accounts {spec}")),
+        }
+    }
+
+    // stdin is reserved for CustomerIDs when using `--accounts @-`
+    let stdin_has_accounts = cli.accounts.as_deref() == Some(util::ACCOUNTS_FROM_STDIN);
+
+    if cli.stdin == "query"
+        && cli.stored_query.is_none()
+        && cli.gaql_query.is_none()
+        && !cli.list_child_accounts
+        && !cli.check_quota
+        && cli.enum_values.is_none()
+        && !cli.changes
+        && cli.dump_resolved_config.is_none()
+        && !stdin_has_accounts
+        // nothing piped in, e.g. to fall back to the profile's default query
+        && !io::stdin().is_terminal()
+    {
         let mut buffer = String::new();
         io::stdin().read_to_string(&mut buffer).expect("Failed to read from stdin");
         if !buffer.trim().is_empty() {
@@ -63,5 +466,39 @@ pub fn parse() -> Cli {
         }
     }
 
+    if let Err(e) = cli.validate() {
+        util::fail(e);
+    }
+
     cli
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(args: &[&str]) -> Result<()> {
+        Cli::parse_from([&["mcc-gaql"], args].concat()).validate()
+    }
+
+    #[test]
+    fn validate_accepts_compatible_flags() {
+        assert!(validate(&["SELECT campaign.id FROM campaign"]).is_ok());
+        assert!(validate(&["--groupby", "segments.device", "--agg", "metrics.clicks:max", "--summary-mode", "only"]).is_ok());
+        assert!(validate(&["--changes", "--since", "7d", "--since-last-run"]).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_conflicting_flags() {
+        let err = |args: &[&str]| validate(args).unwrap_err().to_string();
+
+        assert!(err(&["--changes", "SELECT campaign.id FROM campaign"]).starts_with("--changes"));
+        assert!(err(&["--since", "7d", "--since-last-run"]).contains("--since-last-run"));
+        assert!(err(&["--chunk-param", "ids", "--summary-mode", "only"]).contains("--chunk-param"));
+        assert!(err(&["--chunk-param", "ids", "--query-map", "map.toml"]).contains("--query-map"));
+        assert!(err(&["--summary-mode", "with-results", "--prune-zero-rows"]).contains("with-results"));
+        assert_eq!(err(&["--agg", "metrics.clicks:max"]), "--agg requires --groupby");
+        assert_eq!(err(&["--buckets", "0"]), "--buckets must be at least 1");
+        assert!(err(&["--split-by", "customer_id"]).contains("--output"));
+    }
+}
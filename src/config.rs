@@ -3,11 +3,13 @@ use figment::{
     Figment,
 };
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
 const TOML_CONFIG_FILENAME: &str = "config.toml";
 pub const ENV_VAR_PREFIX: &str = "MCC_GAQL_";
+/// ENV VAR naming a config file to use instead of the platform default
+pub const CONFIG_PATH_ENV_VAR: &str = "MCC_GAQL_CONFIG";
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct MyConfig {
@@ -19,16 +21,38 @@ pub struct MyConfig {
     pub customerids_filename: Option<String>,
     /// Optional TOML file with stored queries
     pub queries_filename: Option<String>,
+    /// Optional max GAQL query length in chars, checked before sending
+    pub max_query_length: Option<usize>,
+    /// Optional PEM file with extra CA certificate(s) to trust, e.g. for TLS-inspecting proxies
+    pub tls_ca_cert_path: Option<String>,
+    /// Optional GAQL query to run when none is given on the command line, stdin or via stored query
+    pub default_query: Option<String>,
+    /// Optional extra metrics, without `metrics.` prefix, that `--humanize-metrics` converts from fractions to percentages
+    pub percent_metrics: Option<Vec<String>>,
+    /// Optional max rows to print as a table on a terminal before asking for confirmation
+    pub max_table_rows: Option<usize>,
 }
 
-pub fn load(profile: &str) -> anyhow::Result<MyConfig> {
+/// Load config for profile from `config_path`, else the `MCC_GAQL_CONFIG` file, else the platform config dir
+pub fn load(profile: &str, config_path: Option<&Path>) -> anyhow::Result<MyConfig> {
     log::info!("Config profile: {profile}");
 
     // load config file to get mcc_customer_id
     let mut figment: Figment = Figment::new();
 
-    // load from file if present
-    if let Some(config_file_path) = config_file_path(TOML_CONFIG_FILENAME) {
+    let explicit_path = config_path
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(CONFIG_PATH_ENV_VAR).map(PathBuf::from));
+
+    if let Some(config_file_path) = explicit_path {
+        // a config file asked for by name must exist
+        if !config_file_path.is_file() {
+            anyhow::bail!("Config file not found: {}", config_file_path.display());
+        }
+        log::debug!("Loading config file: {:?}", config_file_path);
+        figment = figment.merge(Toml::file(config_file_path).nested());
+    } else if let Some(config_file_path) = config_file_path(TOML_CONFIG_FILENAME) {
+        // load from file if present
         log::debug!("Loading config file: {:?}", config_file_path);
         figment = figment.merge(Toml::file(config_file_path).nested());
     }
@@ -47,3 +71,94 @@ pub fn config_file_path(filename: &str) -> Option<PathBuf> {
         path
     })
 }
+
+/// get the platform-correct cache file path
+pub fn cache_file_path(filename: &str) -> Option<PathBuf> {
+    dirs::cache_dir().map(move |mut path| {
+        path.push(CRATE_NAME);
+        path.push(filename);
+        path
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// serializes tests that set `MCC_GAQL_CONFIG`, since the environment is shared across test threads
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn profile_config(mcc_customerid: &str) -> String {
+        format!("[dailyspend]\nmcc_customerid = \"{mcc_customerid}\"\ntoken_cache_filename = \"tokencache.json\"\n")
+    }
+
+    /// write config file contents to a temp file unique to this test
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{CRATE_NAME}-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn default_query_round_trips() {
+        let query = "SELECT campaign.id, metrics.cost_micros FROM campaign WHERE segments.date DURING YESTERDAY";
+        let config: MyConfig = toml::from_str(&format!(
+            "mcc_customerid = \"1234567890\"\ntoken_cache_filename = \"tokencache.json\"\ndefault_query = \"{query}\"\n"
+        ))
+        .unwrap();
+
+        let path = write_config("default-query", &format!("[dailyspend]\n{}", toml::to_string(&config).unwrap()));
+        let loaded = load("dailyspend", Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.default_query.as_deref(), Some(query));
+    }
+
+    #[test]
+    fn default_query_is_optional() {
+        let path = write_config("no-default-query", &profile_config("1234567890"));
+        let loaded = load("dailyspend", Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.default_query, None);
+        assert!(!toml::to_string(&loaded).unwrap().contains("default_query"));
+    }
+
+    #[test]
+    fn load_from_env_var_path() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let path = write_config("env-var", &profile_config("1111111111"));
+
+        std::env::set_var(CONFIG_PATH_ENV_VAR, &path);
+        let loaded = load("dailyspend", None);
+        std::env::remove_var(CONFIG_PATH_ENV_VAR);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap().mcc_customerid, "1111111111");
+    }
+
+    #[test]
+    fn config_path_overrides_env_var() {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let env_path = write_config("overridden", &profile_config("1111111111"));
+        let path = write_config("override", &profile_config("2222222222"));
+
+        std::env::set_var(CONFIG_PATH_ENV_VAR, &env_path);
+        let loaded = load("dailyspend", Some(&path));
+        std::env::remove_var(CONFIG_PATH_ENV_VAR);
+        std::fs::remove_file(&env_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap().mcc_customerid, "2222222222");
+    }
+
+    #[test]
+    fn missing_config_path_is_an_error() {
+        let path = std::env::temp_dir().join(format!("{CRATE_NAME}-{}-missing.toml", std::process::id()));
+
+        let err = load("dailyspend", Some(&path)).unwrap_err().to_string();
+
+        assert!(err.starts_with("Config file not found"), "{err}");
+    }
+}
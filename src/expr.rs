@@ -0,0 +1,348 @@
+//! Minimal expression language for `--expr` post-processing steps.
+//!
+//! Each step is one of:
+//!   filter <expr>
+//!   with_column <name> = <expr>
+//!   select <col>, <col>, ...
+//!
+//! Expressions support column names (e.g. `metrics.clicks`, or `"odd name"` when quoted),
+//! numbers, 'strings', true/false, `+ - * /`, `== != < <= > >=`, `&& || !` and parentheses.
+//! Division always produces floats.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use polars::prelude::*;
+
+/// A parsed post-processing step
+pub enum Step {
+    Filter(Expr),
+    WithColumn(String, Expr),
+    Select(Vec<String>),
+}
+
+/// A step along with the columns it references, for validation before execution
+pub struct ParsedStep {
+    step: Step,
+    columns: HashSet<String>,
+    source: String,
+}
+
+/// Parse a `--expr` step
+pub fn parse_step(input: &str) -> Result<ParsedStep> {
+    let input = input.trim();
+    let (keyword, rest) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let rest = rest.trim();
+
+    let (step, columns) = match keyword {
+        "filter" => {
+            let (expr, columns) = parse_expr(rest)?;
+            (Step::Filter(expr), columns)
+        }
+        "with_column" => {
+            let Some((name, expr_str)) = rest.split_once('=') else {
+                bail!("expected `with_column <name> = <expr>`");
+            };
+            let name = unquote_column(name.trim())?;
+            if expr_str.starts_with('=') {
+                bail!("expected `with_column <name> = <expr>`");
+            }
+            let (expr, columns) = parse_expr(expr_str)?;
+            (Step::WithColumn(name.clone(), expr.alias(&name)), columns)
+        }
+        "select" => {
+            let names = rest
+                .split(',')
+                .map(|c| unquote_column(c.trim()))
+                .collect::<Result<Vec<_>>>()?;
+            let columns = names.iter().cloned().collect();
+            (Step::Select(names), columns)
+        }
+        _ => bail!("unknown step '{keyword}', expected filter, with_column or select"),
+    };
+
+    Ok(ParsedStep {
+        step,
+        columns,
+        source: input.to_owned(),
+    })
+}
+
+/// Parse an expression, returning it with the set of column names it references
+pub fn parse_expr(input: &str) -> Result<(Expr, HashSet<String>)> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        bail!("empty expression");
+    }
+
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        columns: HashSet::new(),
+    };
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.peek() {
+        bail!("unexpected {token:?} in expression '{input}'");
+    }
+
+    Ok((expr, parser.columns))
+}
+
+/// Apply steps in order, checking referenced columns exist at each step
+pub fn apply_steps(df: DataFrame, steps: &[ParsedStep]) -> Result<DataFrame> {
+    let mut available: HashSet<String> = df
+        .get_column_names()
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+    let mut lf = df.lazy();
+
+    for parsed in steps {
+        check_columns(&parsed.columns, &available, &parsed.source)?;
+
+        lf = match &parsed.step {
+            Step::Filter(expr) => lf.filter(expr.clone()),
+            Step::WithColumn(name, expr) => {
+                available.insert(name.clone());
+                lf.with_column(expr.clone())
+            }
+            Step::Select(names) => {
+                available = names.iter().cloned().collect();
+                lf.select(names.iter().map(|n| col(n)).collect::<Vec<_>>())
+            }
+        };
+    }
+
+    Ok(lf.collect()?)
+}
+
+/// Fail if any referenced column is not available
+pub fn check_columns(
+    referenced: &HashSet<String>,
+    available: &HashSet<String>,
+    source: &str,
+) -> Result<()> {
+    let mut missing: Vec<&String> = referenced.difference(available).collect();
+    if !missing.is_empty() {
+        missing.sort();
+        bail!("'{source}' references unknown columns: {missing:?}");
+    }
+
+    Ok(())
+}
+
+fn unquote_column(s: &str) -> Result<String> {
+    match tokenize(s)?.as_slice() {
+        [Token::Ident(name)] => Ok(name.clone()),
+        _ => bail!("invalid column name '{s}'"),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+}
+
+const OPERATORS: [&str; 13] = [
+    "==", "!=", "<=", ">=", "&&", "||", "<", ">", "+", "-", "*", "/", "!",
+];
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            // quoted string literal, or quoted column name
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => bail!("unterminated quote in '{input}'"),
+                    Some('\\') if chars.get(i + 1).is_some() => {
+                        value.push(chars[i + 1]);
+                        i += 2;
+                    }
+                    Some(&q) if q == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&other) => {
+                        value.push(other);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(if c == '\'' {
+                Token::Str(value)
+            } else {
+                Token::Ident(value)
+            });
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens.push(match literal.parse::<i64>() {
+                Ok(n) => Token::Int(n),
+                Err(_) => match literal.parse::<f64>() {
+                    Ok(n) => Token::Float(n),
+                    Err(_) => bail!("invalid number '{literal}'"),
+                },
+            });
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if let Some(op) = OPERATORS.iter().find(|op| {
+            op.chars()
+                .enumerate()
+                .all(|(j, oc)| chars.get(i + j) == Some(&oc))
+        }) {
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else {
+            bail!("unexpected character '{c}' in '{input}'");
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    columns: HashSet<String>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// consume the next token if it is one of the given operators
+    fn next_op(&mut self, ops: &[&'static str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(Token::Op(op)) if ops.contains(op) => {
+                let op = *op;
+                self.pos += 1;
+                Some(op)
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.next_op(&["||"]).is_some() {
+            expr = expr.or(self.parse_and()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_comparison()?;
+        while self.next_op(&["&&"]).is_some() {
+            expr = expr.and(self.parse_comparison()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr> {
+        let lhs = self.parse_additive()?;
+        let Some(op) = self.next_op(&["==", "!=", "<", "<=", ">", ">="]) else {
+            return Ok(lhs);
+        };
+        let rhs = self.parse_additive()?;
+
+        Ok(match op {
+            "==" => lhs.eq(rhs),
+            "!=" => lhs.neq(rhs),
+            "<" => lhs.lt(rhs),
+            "<=" => lhs.lt_eq(rhs),
+            ">" => lhs.gt(rhs),
+            _ => lhs.gt_eq(rhs),
+        })
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_multiplicative()?;
+        while let Some(op) = self.next_op(&["+", "-"]) {
+            let rhs = self.parse_multiplicative()?;
+            expr = if op == "+" { expr + rhs } else { expr - rhs };
+        }
+        Ok(expr)
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        while let Some(op) = self.next_op(&["*", "/"]) {
+            let rhs = self.parse_unary()?;
+            expr = if op == "*" {
+                expr * rhs
+            } else {
+                expr.cast(DataType::Float64) / rhs.cast(DataType::Float64)
+            };
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.next_op(&["-"]).is_some() {
+            return Ok(lit(0) - self.parse_unary()?);
+        }
+        if self.next_op(&["!"]).is_some() {
+            return Ok(self.parse_unary()?.not());
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Int(n)) => Ok(lit(n)),
+            Some(Token::Float(n)) => Ok(lit(n)),
+            Some(Token::Str(s)) => Ok(lit(s)),
+            Some(Token::Ident(name)) if name == "true" => Ok(lit(true)),
+            Some(Token::Ident(name)) if name == "false" => Ok(lit(false)),
+            Some(Token::Ident(name)) => {
+                self.columns.insert(name.clone());
+                Ok(col(&name))
+            }
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => bail!("missing closing parenthesis"),
+                }
+            }
+            Some(token) => bail!("unexpected {token:?}"),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+}
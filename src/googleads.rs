@@ -1,13 +1,24 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
 use polars::prelude::*;
+use serde::{Deserialize, Serialize};
+use thousands::Separable;
 use tokio_stream::StreamExt;
 use tonic::{
     codegen::InterceptedService,
     metadata::{Ascii, MetadataValue},
     service::Interceptor,
-    transport::Channel,
+    transport::{Certificate, Channel, ClientTlsConfig},
     Response, Status, Streaming,
 };
 use yup_oauth2::{
@@ -17,13 +28,34 @@ use yup_oauth2::{
 
 use googleads_rs::google::ads::googleads::v18::services::google_ads_field_service_client::GoogleAdsFieldServiceClient;
 use googleads_rs::google::ads::googleads::v18::services::google_ads_service_client::GoogleAdsServiceClient;
+use googleads_rs::google::ads::googleads::v18::enums::google_ads_field_data_type_enum::GoogleAdsFieldDataType;
+use googleads_rs::google::ads::googleads::v18::enums::summary_row_setting_enum::SummaryRowSetting;
 use googleads_rs::google::ads::googleads::v18::resources::GoogleAdsField;
 use googleads_rs::google::ads::googleads::v18::services::{
-    GoogleAdsRow, SearchGoogleAdsFieldsRequest, SearchGoogleAdsFieldsResponse,
+    GoogleAdsRow, SearchGoogleAdsFieldsRequest, SearchGoogleAdsFieldsResponse, SearchGoogleAdsRequest,
     SearchGoogleAdsStreamRequest, SearchGoogleAdsStreamResponse,
 };
 
-use async_std::io::WriteExt;
+// change_event requires a date filter within the lookback window and a LIMIT
+const CHANGE_EVENT_QUERY: &str = "
+SELECT
+    customer.id,
+    change_event.change_date_time,
+    change_event.change_resource_type,
+    change_event.change_resource_name,
+    change_event.resource_change_operation,
+    change_event.changed_fields,
+    change_event.client_type,
+    change_event.user_email
+FROM change_event
+WHERE
+    change_event.change_date_time >= '{since} 00:00:00'
+    and change_event.change_date_time <= '{until} 23:59:59'
+ORDER BY change_event.change_date_time DESC
+LIMIT {limit}
+";
+const CHANGE_EVENT_LOOKBACK_DAYS: i64 = 30;
+const CHANGE_EVENT_MAX_LIMIT: usize = 10000;
 
 pub const SUB_ACCOUNTS_QUERY: &str = "
 SELECT
@@ -54,7 +86,22 @@ WHERE
 ORDER BY customer_client.level, customer_client.id
 ";
 
+/// Cheapest possible query, used to probe API access and consumption
+pub const QUOTA_PROBE_QUERY: &str = "SELECT customer.id FROM customer LIMIT 1";
+
+/// Conservative default max GAQL query length in chars, overridable via `max_query_length` config
+pub const DEFAULT_MAX_QUERY_LENGTH: usize = 128 * 1024;
+
+/// Google Ads API version of the generated clients in use
+pub const API_VERSION: &str = "v18";
+
+/// Max requests per second sent over the API channel
+pub const RATE_LIMIT_PER_SEC: u64 = 100;
+/// Max in-flight requests over the API channel
+pub const CONCURRENCY_LIMIT: usize = 100;
+
 const ENDPOINT: &str = "https://googleads.googleapis.com:443";
+const ENDPOINT_DOMAIN: &str = "googleads.googleapis.com";
 // dev token borrowed from https://github.com/selesnow/rgoogleads/blob/master/R/gads_auth.R
 const DEV_TOKEN: &str = "EBkkx-znu2cZcEY7e74smg";
 
@@ -63,11 +110,11 @@ const FILENAME_CLIENT_SECRET: &str = "clientsecret.json";
 static GOOGLE_ADS_API_SCOPE: &str = "https://www.googleapis.com/auth/adwords";
 
 // incomplete. Only what I need for the moment.
+// Parsed as i64 rather than u64: polars finds no supertype for u64 and an int literal, which `--expr` arithmetic needs
 const GOOGLE_ADS_METRICS_INTEGER_FIELDS: &[&str] = &[
     "clicks",
     "cost_micros",
     "engagements",
-    "historical_creative_quality_score",
     "historical_quality_score",
     "impressions",
     "interactions",
@@ -79,51 +126,148 @@ const GOOGLE_ADS_METRICS_INTEGER_FIELDS: &[&str] = &[
     "view_through_conversions"
 ];
 
+/// DATE fields not matched by the `.date`/`_date` name rule
+const GOOGLE_ADS_DATE_FIELDS: &[&str] = &[
+    "segments.month",
+    "segments.quarter",
+    "segments.week",
+];
+
+/// DATETIME value formats without a UTC offset, with optional fractional seconds
+const DATETIME_FORMATS: &[&str] = &["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+/// Metrics reported as fractions (0.05 = 5%), converted to percentages by `--humanize-metrics`.
+/// Extend with `percent_metrics` in config.
+const GOOGLE_ADS_METRICS_PERCENT_FIELDS: &[&str] = &[
+    "absolute_top_impression_percentage",
+    "all_conversions_from_interactions_rate",
+    "content_budget_lost_impression_share",
+    "content_impression_share",
+    "content_rank_lost_impression_share",
+    "conversions_from_interactions_rate",
+    "ctr",
+    "engagement_rate",
+    "interaction_rate",
+    "invalid_click_rate",
+    "search_absolute_top_impression_share",
+    "search_budget_lost_absolute_top_impression_share",
+    "search_budget_lost_impression_share",
+    "search_budget_lost_top_impression_share",
+    "search_exact_match_impression_share",
+    "search_impression_share",
+    "search_rank_lost_absolute_top_impression_share",
+    "search_rank_lost_impression_share",
+    "search_rank_lost_top_impression_share",
+    "search_top_impression_share",
+    "top_impression_percentage",
+    "video_view_rate",
+];
+
+/// Resources whose canonical resource_name can be rebuilt from `<resource>.id` and the customer_id,
+/// mapped to their resource_name collection segment
+const RESOURCE_NAME_COLLECTIONS: &[(&str, &str)] = &[
+    ("ad_group", "adGroups"),
+    ("asset", "assets"),
+    ("asset_group", "assetGroups"),
+    ("asset_set", "assetSets"),
+    ("bidding_strategy", "biddingStrategies"),
+    ("campaign", "campaigns"),
+    ("campaign_budget", "campaignBudgets"),
+    ("conversion_action", "conversionActions"),
+    ("customer_client", "customerClients"),
+    ("label", "labels"),
+    ("user_list", "userLists"),
+];
+
+/// Values of commonly filtered ENUM fields, used when GoogleAdsFieldService returns none.
+/// UNSPECIFIED and UNKNOWN are omitted since they are never useful in filters.
+const KNOWN_ENUM_VALUES: &[(&str, &[&str])] = &[
+    ("ad_group.status", &["ENABLED", "PAUSED", "REMOVED"]),
+    ("ad_group_ad.status", &["ENABLED", "PAUSED", "REMOVED"]),
+    ("ad_group_criterion.status", &["ENABLED", "PAUSED", "REMOVED"]),
+    ("campaign.status", &["ENABLED", "PAUSED", "REMOVED"]),
+    ("campaign.serving_status", &["SERVING", "NONE", "ENDED", "PENDING", "SUSPENDED"]),
+    (
+        "campaign.advertising_channel_type",
+        &[
+            "SEARCH", "DISPLAY", "SHOPPING", "HOTEL", "VIDEO", "MULTI_CHANNEL", "LOCAL",
+            "SMART", "PERFORMANCE_MAX", "LOCAL_SERVICES", "TRAVEL", "DEMAND_GEN",
+        ],
+    ),
+    ("customer.status", &["ENABLED", "CANCELED", "SUSPENDED", "CLOSED"]),
+    ("customer_client.status", &["ENABLED", "CANCELED", "SUSPENDED", "CLOSED"]),
+    ("segments.ad_network_type", &["SEARCH", "SEARCH_PARTNERS", "CONTENT", "MIXED", "YOUTUBE", "GOOGLE_TV"]),
+    ("segments.device", &["MOBILE", "TABLET", "DESKTOP", "CONNECTED_TV", "OTHER"]),
+];
+
+/// GoogleAdsService client whose requests are authorized by a GoogleAdsAPIAccess
+pub type GoogleAdsClient = GoogleAdsServiceClient<InterceptedService<Channel, GoogleAdsAPIAccess>>;
+
+/// Clones share the access token, so a renewal is seen by every client built from this context
 #[derive(Clone)]
 pub struct GoogleAdsAPIAccess {
     pub channel: Channel,
     pub dev_token: MetadataValue<Ascii>,
     pub login_customer: MetadataValue<Ascii>,
-    pub auth_token: Option<MetadataValue<Ascii>>,
-    pub token: Option<AccessToken>,
+    pub auth_token: Arc<RwLock<Option<MetadataValue<Ascii>>>>,
+    pub token: Arc<RwLock<Option<AccessToken>>>,
     pub authenticator: Authenticator<<DefaultHyperClient as HyperClientBuilder>::Connector>,
 }
 
 impl GoogleAdsAPIAccess {
     /// Renews Access Token if none exists or if almost expired
     /// returns True if token renewed
-    pub async fn renew_token(&mut self) -> Result<bool> {
-        let mut renewed: bool = false;
-        if self.token.is_none() || self.token.as_ref().unwrap().is_expired() {
-            self.token = match self
-                .authenticator
-                .force_refreshed_token(&[GOOGLE_ADS_API_SCOPE])
-                .await
-            {
-                Err(e) => {
-                    bail!("failed to get access token: {:?}", e);
-                }
-                Ok(t) => {
-                    log::debug!("Obtained access token: {t:?}");
+    pub async fn renew_token(&self) -> Result<bool> {
+        let expired = self
+            .token
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_none_or(AccessToken::is_expired);
+        if !expired {
+            return Ok(false);
+        }
 
-                    let bearer_token = format!("Bearer {}", t.as_str());
-                    let header_value_auth_token = MetadataValue::try_from(&bearer_token)?;
-                    self.auth_token = Some(header_value_auth_token);
+        match self
+            .authenticator
+            .force_refreshed_token(&[GOOGLE_ADS_API_SCOPE])
+            .await
+        {
+            Err(e) => {
+                bail!("failed to get access token: {:?}", e);
+            }
+            Ok(t) => {
+                log::debug!("Obtained access token: {t:?}");
 
-                    renewed = true;
-                    Some(t)
-                }
-            };
+                let bearer_token = format!("Bearer {}", t.as_str());
+                let header_value_auth_token = MetadataValue::try_from(&bearer_token)?;
+                *self.auth_token.write().unwrap() = Some(header_value_auth_token);
+                *self.token.write().unwrap() = Some(t);
+
+                Ok(true)
+            }
         }
-        Ok(renewed)
+    }
+
+    /// Build a GoogleAdsService client on the shared channel and access token
+    pub fn client(&self) -> GoogleAdsClient {
+        GoogleAdsServiceClient::with_interceptor(self.channel.clone(), self.clone())
+    }
+
+    /// Same access, but sending a different manager account as login-customer-id
+    pub fn with_login_customer(&self, mcc_customer_id: &str) -> Result<GoogleAdsAPIAccess> {
+        let mut access = self.clone();
+        access.login_customer = MetadataValue::try_from(mcc_customer_id)?;
+        Ok(access)
     }
 }
 
 impl Interceptor for GoogleAdsAPIAccess {
     fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
-        request
-            .metadata_mut()
-            .insert("authorization", self.auth_token.as_ref().unwrap().clone());
+        let Some(auth_token) = self.auth_token.read().unwrap().clone() else {
+            return Err(Status::unauthenticated("No access token"));
+        };
+        request.metadata_mut().insert("authorization", auth_token);
         request
             .metadata_mut()
             .insert("developer-token", self.dev_token.clone());
@@ -139,39 +283,48 @@ impl Interceptor for GoogleAdsAPIAccess {
 pub async fn get_api_access(
     mcc_customer_id: &str,
     token_cache_filename: &str,
+    tls_ca_cert_path: Option<&str>,
 ) -> Result<GoogleAdsAPIAccess> {
-    let client_secret_path =
-        crate::config::config_file_path(FILENAME_CLIENT_SECRET).expect("clientsecret path");
+    let Some(client_secret_path) = crate::config::config_file_path(FILENAME_CLIENT_SECRET) else {
+        return Err(ClientSecretError("Unable to locate config dir for OAuth client secret".to_owned()).into());
+    };
 
     let app_secret: ApplicationSecret =
-        yup_oauth2::read_application_secret(client_secret_path.as_path())
-            .await
-            .expect("clientsecret.json");
+        match yup_oauth2::read_application_secret(client_secret_path.as_path()).await {
+            Ok(secret) => secret,
+            Err(e) => {
+                let problem = if e.kind() == std::io::ErrorKind::NotFound {
+                    "OAuth client secret not found".to_owned()
+                } else {
+                    format!("Unable to read OAuth client secret: {e}")
+                };
+                return Err(ClientSecretError(format!(
+                    "{problem} at {}.\n\
+                    Create an OAuth client ID of type Desktop app in Google Cloud Console \
+                    (APIs & Services > Credentials) for a project with the Google Ads API enabled, \
+                    then download its JSON and save it to that path.",
+                    client_secret_path.display()
+                ))
+                .into());
+            }
+        };
 
     let token_cache_path =
         crate::config::config_file_path(token_cache_filename).expect("token cache path");
 
-    let auth: Authenticator<<DefaultHyperClient as HyperClientBuilder>::Connector> =
-        InstalledFlowAuthenticator::builder(app_secret, InstalledFlowReturnMethod::HTTPRedirect)
-            .persist_tokens_to_disk(token_cache_path.as_path())
-            .build()
-            .await?;
+    let auth = build_authenticator(app_secret, &token_cache_path).await?;
 
     let header_value_dev_token = MetadataValue::try_from(DEV_TOKEN)?;
     let header_value_login_customer = MetadataValue::try_from(mcc_customer_id)?;
 
-    let channel: Channel = Channel::from_static(ENDPOINT)
-        .rate_limit(100, Duration::from_secs(1))
-        .concurrency_limit(100)
-        .connect()
-        .await?;
+    let channel: Channel = connect_channel(tls_ca_cert_path).await?;
 
-    let mut access = GoogleAdsAPIAccess {
+    let access = GoogleAdsAPIAccess {
         channel,
         dev_token: header_value_dev_token,
         login_customer: header_value_login_customer,
-        auth_token: None,
-        token: None,
+        auth_token: Arc::new(RwLock::new(None)),
+        token: Arc::new(RwLock::new(None)),
         authenticator: auth,
     };
 
@@ -180,113 +333,735 @@ pub async fn get_api_access(
     Ok(access)
 }
 
-/// Run query via GoogleAdsServiceClient to get performance data
+/// OAuth2 installed-flow authenticator, persisting tokens to `token_cache_path`, which is read on build
+async fn build_authenticator(
+    app_secret: ApplicationSecret,
+    token_cache_path: &Path,
+) -> Result<Authenticator<<DefaultHyperClient as HyperClientBuilder>::Connector>> {
+    match InstalledFlowAuthenticator::builder(app_secret, InstalledFlowReturnMethod::HTTPRedirect)
+        .persist_tokens_to_disk(token_cache_path)
+        .build()
+        .await
+    {
+        Ok(auth) => Ok(auth),
+        // cache file exists but does not parse, e.g. after a partial write
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Err(TokenCacheError(format!(
+            "Token cache {} is corrupt: {e}",
+            token_cache_path.display()
+        ))
+        .into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Token cache file that cannot be parsed, fixed by removing it and re-authenticating
+#[derive(Debug)]
+pub struct TokenCacheError(String);
+
+impl fmt::Display for TokenCacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TokenCacheError {}
+
+/// Missing or unreadable OAuth client secret, which re-auth cannot fix
+#[derive(Debug)]
+pub struct ClientSecretError(String);
+
+impl fmt::Display for ClientSecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ClientSecretError {}
+
+/// Failure to set up the API channel (network, TLS), as opposed to an OAuth failure
+#[derive(Debug)]
+pub struct ChannelError(String);
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ChannelError {}
+
+/// Connect to the Google Ads API endpoint.
+///
+/// Trusts both native and bundled (webpki) root certificates, plus an optional custom CA.
+async fn connect_channel(tls_ca_cert_path: Option<&str>) -> Result<Channel, ChannelError> {
+    let mut endpoint = Channel::from_static(ENDPOINT)
+        .rate_limit(RATE_LIMIT_PER_SEC, Duration::from_secs(1))
+        .concurrency_limit(CONCURRENCY_LIMIT);
+
+    if let Some(ca_cert_path) = tls_ca_cert_path {
+        let pem = std::fs::read(ca_cert_path).map_err(|e| {
+            ChannelError(format!("Unable to read TLS CA certificate file {ca_cert_path}: {e}"))
+        })?;
+        let tls_config = ClientTlsConfig::new()
+            .ca_certificate(Certificate::from_pem(pem))
+            .domain_name(ENDPOINT_DOMAIN);
+        endpoint = endpoint.tls_config(tls_config).map_err(|e| {
+            ChannelError(format!("Invalid TLS CA certificate file {ca_cert_path}: {e:?}"))
+        })?;
+    }
+
+    endpoint.connect().await.map_err(|e| {
+        let is_tls_error = std::iter::successors(Some(&e as &dyn std::error::Error), |e| {
+            e.source()
+        })
+        .any(|e| e.to_string().contains("certificate"));
+
+        if is_tls_error {
+            ChannelError(format!(
+                "TLS certificate verification failed connecting to {ENDPOINT}: {e:?}. \
+                If behind a TLS-inspecting proxy, set `tls_ca_cert_path` in config to its CA certificate."
+            ))
+        } else {
+            ChannelError(format!("Network error connecting to {ENDPOINT}: {e:?}"))
+        }
+    })
+}
+
+/// Run query via GoogleAdsServiceClient to get performance data.
+/// Stops reading the response stream after `max_rows` rows, returning whether results were truncated.
 pub async fn gaql_query_with_client(
-    mut client: GoogleAdsServiceClient<InterceptedService<Channel, GoogleAdsAPIAccess>>,
+    mut client: GoogleAdsClient,
     customer_id: String,
     query: String,
-) -> Result<(DataFrame, i64)> {
+    summary_row_setting: SummaryRowSetting,
+    trace_dir: Option<PathBuf>,
+    max_rows: Option<usize>,
+) -> Result<(DataFrame, i64, bool)> {
+    let requested_fields = crate::util::get_select_fields(&query);
+
+    // raw responses are written before DataFrame conversion, to separate API output from parsing
+    let mut trace_file = match &trace_dir {
+        Some(dir) => {
+            let path = dir.join(format!("{customer_id}.trace"));
+            let mut f = BufWriter::new(File::create(&path)?);
+            writeln!(f, "customer_id: {customer_id}\nquery: {query}\n")?;
+            log::debug!("Tracing responses for account {customer_id} to {}", path.display());
+            Some(f)
+        }
+        None => None,
+    };
+
     let result: Result<Response<Streaming<SearchGoogleAdsStreamResponse>>, Status> = client
         .search_stream(SearchGoogleAdsStreamRequest {
             customer_id: customer_id.clone(),
             query,
-            summary_row_setting: 0,
+            summary_row_setting: summary_row_setting as i32,
         })
         .await;
 
+    let mut truncated = false;
+
     let (df, total_api_consumption) = match result {
         Ok(response) => {
             let mut stream = response.into_inner();
 
-            let mut columns: Vec<Vec<String>> = Vec::new();
-            let mut headers: Option<Vec<String>> = None;
+            let mut response_columns = ResponseColumns::default();
             let mut api_consumption: i64 = 0;
+            let mut row_count: usize = 0;
 
-            while let Some(item) = stream.next().await {
+            'stream: while let Some(item) = stream.next().await {
                 match item {
                     Ok(stream_response) => {
+                        if let Some(f) = &mut trace_file {
+                            writeln!(f, "{stream_response:#?}")?;
+                        }
+
                         // aggregate api consumption
                         api_consumption += stream_response.query_resource_consumption;
 
-                        let field_mask = stream_response.field_mask.unwrap();
-                        if headers.is_none() {
-                            headers = Some(field_mask.paths.clone());
+                        let field_mask_paths = stream_response.field_mask.as_ref().map(|m| m.paths.as_slice());
+                        if !response_columns.use_field_mask(&customer_id, &requested_fields, field_mask_paths)? {
+                            continue;
                         }
-                        for r in stream_response.results {
-                            let row: GoogleAdsRow = r;
-
-                            // go through all columns specified in query, pull out string value, and insert into columns
-                            for i in 0..headers.as_ref().unwrap().len() {
-                                let path = &headers.as_ref().unwrap()[i];
-                                let string_val: String = row.get(path).trim_matches('"').to_string();
-                                match columns.get_mut(i) {
-                                    Some(v) => {
-                                        v.push(string_val);
-                                    }
-                                    None => {
-                                        let v: Vec<String> = vec![string_val];
-                                        columns.insert(i, v);
-                                    }
-                                }
+                        for row in stream_response.results {
+                            // dropping the stream cancels the rest of the response
+                            if max_rows.is_some_and(|max| row_count >= max) {
+                                log::warn!("Account {customer_id}: truncated results at {row_count} rows");
+                                truncated = true;
+                                break 'stream;
                             }
+                            row_count += 1;
+
+                            response_columns.push_row(&row);
+                        }
+
+                        if let Some(row) = stream_response.summary_row {
+                            response_columns.push_summary_row(&row);
                         }
                     }
                     Err(status) => {
-                        bail!(
+                        if let Some(f) = &mut trace_file {
+                            writeln!(f, "error: {status:#?}")?;
+                        }
+                        let msg = format!(
                             "GoogleAdsClient streaming error. Account: {customer_id}, Message: {}, Details: {}",
                             status.message(),
                             String::from_utf8_lossy(status.details()).into_owned()
                         );
+                        return Err(anyhow::Error::new(status).context(msg));
                     }
                 }
             }
 
-            let mut series_vec: Vec<Series> = Vec::new();
-
-            // convert columnar values (String) into Polars Series with right datatype
-            //  - metric columns could be Integer or Float
-            //  - other columns are String
-            if let Some(headers_vec) = headers {
-                for (i, header) in headers_vec.iter().enumerate() {
-                    if header.starts_with("metrics") {
-                        if GOOGLE_ADS_METRICS_INTEGER_FIELDS.iter().any(|f| f==header) {
-                            let v: Vec<u64> = columns
-                                .get(i)
-                                .unwrap()
-                                .iter()
-                                .map(|x| x.parse::<u64>().unwrap())
-                                .collect();
-                            series_vec.push(Series::new(header, v));
-                        } else {
-                            let v: Vec<f64> = columns
-                                .get(i)
-                                .unwrap()
-                                .iter()
-                                .map(|x| x.parse::<f64>().unwrap())
-                                .collect();
-                            series_vec.push(Series::new(header, v));
-                        }
-                    } else {
-                        let v: &Vec<String> = columns.get(i).unwrap();
-                        series_vec.push(Series::new(header, v));
-                    };
-                }
-            }
-
-            let df = DataFrame::new(series_vec).unwrap();
-
-            (df, api_consumption)
+            (response_columns.into_dataframe()?, api_consumption)
         }
         Err(status) => {
-            bail!(
+            let msg = format!(
                 "GoogleAdsClient request error. Account: {customer_id}, Message: {}, Details: {}",
                 status.message(),
                 String::from_utf8_lossy(status.details()).into_owned()
             );
+            return Err(anyhow::Error::new(status).context(msg));
+        }
+    };
+
+    Ok((df, total_api_consumption, truncated))
+}
+
+/// Column values collected from search_stream response chunks, named by the first field mask seen
+#[derive(Default)]
+struct ResponseColumns {
+    headers: Option<Vec<String>>,
+    columns: Vec<Vec<String>>,
+}
+
+impl ResponseColumns {
+    /// Take column names from a chunk's field mask, if not known yet. Returns false for chunks to skip:
+    /// chunks without a field mask reuse headers seen so far, or are skipped if there are none yet
+    fn use_field_mask(&mut self, customer_id: &str, requested: &[String], paths: Option<&[String]>) -> Result<bool> {
+        match (paths, &self.headers) {
+            (Some(paths), None) => {
+                log_field_mask_discrepancies(customer_id, requested, paths);
+                if let Some(duplicate) = find_duplicate(paths) {
+                    bail!(
+                        "Duplicate column '{duplicate}' in results for account {customer_id}. Remove repeated fields from SELECT."
+                    );
+                }
+                self.headers = Some(paths.to_vec());
+            }
+            (None, None) => {
+                log::debug!("Account {customer_id}: skipping response chunk without field mask");
+                return Ok(false);
+            }
+            (None, Some(_)) => {
+                log::debug!("Account {customer_id}: response chunk without field mask, using previous headers");
+            }
+            (Some(_), Some(_)) => {}
+        }
+
+        Ok(true)
+    }
+
+    fn push_row(&mut self, row: &GoogleAdsRow) {
+        let Some(headers) = &self.headers else {
+            return;
+        };
+
+        // go through all columns specified in query, pull out string value, and insert into columns
+        for (i, path) in headers.iter().enumerate() {
+            let string_val: String = row.get(path).trim_matches('"').to_string();
+            push_column_value(&mut self.columns, i, string_val);
+        }
+    }
+
+    /// summary row only has metrics, so leave dimension columns empty
+    fn push_summary_row(&mut self, row: &GoogleAdsRow) {
+        let Some(headers) = &self.headers else {
+            return;
+        };
+
+        for (i, path) in headers.iter().enumerate() {
+            let string_val = if path.starts_with("metrics") {
+                row.get(path).trim_matches('"').to_string()
+            } else {
+                String::new()
+            };
+            push_column_value(&mut self.columns, i, string_val);
+        }
+    }
+
+    /// convert columnar values (String) into Polars Series with right datatype
+    ///  - metric columns could be Integer or Float
+    ///  - other columns are String
+    fn into_dataframe(mut self) -> Result<DataFrame> {
+        let mut series_vec: Vec<Series> = Vec::new();
+
+        if let Some(headers_vec) = self.headers {
+            // responses with a field mask but no rows still produce typed, empty columns
+            self.columns.resize(headers_vec.len(), Vec::new());
+
+            for (header, values) in headers_vec.iter().zip(&self.columns) {
+                if header.starts_with("metrics") {
+                    let is_integer = header
+                        .strip_prefix("metrics.")
+                        .is_some_and(|metric| GOOGLE_ADS_METRICS_INTEGER_FIELDS.contains(&metric));
+                    if is_integer {
+                        let v: Vec<Option<i64>> = parse_metric_values(header, values)?;
+                        series_vec.push(Series::new(header, v));
+                    } else {
+                        let v: Vec<Option<f64>> = parse_metric_values(header, values)?;
+                        series_vec.push(Series::new(header, v));
+                    }
+                } else {
+                    series_vec.push(Series::new(header, values));
+                };
+            }
+        }
+
+        Ok(DataFrame::new(series_vec)?)
+    }
+}
+
+/// Parse metric values, leaving empty cells (e.g. metrics missing from the summary row) null
+fn parse_metric_values<T>(header: &str, values: &[String]) -> Result<Vec<Option<T>>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    values
+        .iter()
+        .map(|x| {
+            if x.is_empty() {
+                return Ok(None);
+            }
+            x.parse::<T>()
+                .map(Some)
+                .with_context(|| format!("Invalid value '{x}' in metric column '{header}'"))
+        })
+        .collect()
+}
+
+fn push_column_value(columns: &mut Vec<Vec<String>>, i: usize, value: String) {
+    match columns.get_mut(i) {
+        Some(v) => {
+            v.push(value);
+        }
+        None => {
+            let v: Vec<String> = vec![value];
+            columns.insert(i, v);
+        }
+    }
+}
+
+/// Build a change_event query for a date window, checking the resource's lookback and LIMIT constraints
+pub fn change_event_query(
+    since: NaiveDate,
+    until: NaiveDate,
+    today: NaiveDate,
+    limit: usize,
+) -> Result<String> {
+    if (today - since).num_days() > CHANGE_EVENT_LOOKBACK_DAYS {
+        bail!("change_event only covers the last {CHANGE_EVENT_LOOKBACK_DAYS} days, but window starts {since}");
+    }
+    if limit == 0 || limit > CHANGE_EVENT_MAX_LIMIT {
+        bail!("change_event LIMIT must be between 1 and {CHANGE_EVENT_MAX_LIMIT}, got {limit}");
+    }
+
+    Ok(CHANGE_EVENT_QUERY
+        .replace("{since}", &since.to_string())
+        .replace("{until}", &until.to_string())
+        .replace("{limit}", &limit.to_string()))
+}
+
+/// Map `--summary-mode` value to the API's summary row setting
+pub fn summary_row_setting(mode: &str) -> SummaryRowSetting {
+    match mode {
+        "with-results" => SummaryRowSetting::SummaryRowWithResults,
+        "only" => SummaryRowSetting::SummaryRowOnly,
+        _ => SummaryRowSetting::NoSummaryRow,
+    }
+}
+
+/// Find first repeated column name, which would otherwise fail DataFrame construction
+fn find_duplicate(columns: &[String]) -> Option<&String> {
+    let mut seen: HashSet<&String> = HashSet::new();
+    columns.iter().find(|c| !seen.insert(*c))
+}
+
+/// Log differences between SELECTed fields and the fields returned in the response field mask.
+/// Some divergence is legitimate, so this is purely diagnostic.
+fn log_field_mask_discrepancies(customer_id: &str, requested: &[String], returned: &[String]) {
+    let missing: Vec<&String> = requested.iter().filter(|f| !returned.contains(f)).collect();
+    let extra: Vec<&String> = returned.iter().filter(|f| !requested.contains(f)).collect();
+
+    if !missing.is_empty() {
+        log::debug!("Account {customer_id}: fields requested but not returned: {missing:?}");
+    }
+    if !extra.is_empty() {
+        log::debug!("Account {customer_id}: fields returned but not requested: {extra:?}");
+    }
+}
+
+/// Add `<resource>.resource_name` columns rebuilt client-side from `<resource>.id` columns,
+/// since `omit_unselected_resource_names` drops them from the response.
+/// Resources with composite resource names (e.g. ad_group_ad) are skipped.
+pub fn add_resource_names(df: &mut DataFrame, customer_id: &str) -> Result<()> {
+    let id_columns: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| c.ends_with(".id"))
+        .map(str::to_owned)
+        .collect();
+
+    for id_column in id_columns {
+        let resource = id_column.trim_end_matches(".id");
+        let resource_name_column = format!("{resource}.resource_name");
+        if df.get_column_index(&resource_name_column).is_some() {
+            continue;
+        }
+
+        let prefix = if resource == "customer" {
+            "customers/".to_owned()
+        } else if let Some((_, collection)) =
+            RESOURCE_NAME_COLLECTIONS.iter().find(|(r, _)| *r == resource)
+        {
+            format!("customers/{customer_id}/{collection}/")
+        } else {
+            log::debug!("Unable to reconstruct resource_name for column: {id_column}");
+            continue;
+        };
+
+        let resource_names: Vec<Option<String>> = df
+            .column(&id_column)?
+            .str()?
+            .into_iter()
+            .map(|id| id.map(|id| format!("{prefix}{id}")))
+            .collect();
+
+        let idx = df.try_get_column_index(&id_column)?;
+        df.insert_column(idx + 1, Series::new(&resource_name_column, resource_names))?;
+    }
+
+    Ok(())
+}
+
+/// Convert metric columns to human-friendly units:
+/// `*_micros` are divided by 1,000,000 and lose the suffix (`metrics.cost_micros` -> `metrics.cost`),
+/// fractional rates are multiplied by 100 and gain a `_pct` suffix (`metrics.ctr` -> `metrics.ctr_pct`).
+pub fn humanize_metrics(df: &mut DataFrame, extra_percent_metrics: &[String]) -> Result<()> {
+    let metric_columns: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| c.starts_with("metrics."))
+        .map(str::to_owned)
+        .collect();
+
+    for column in metric_columns {
+        let Some((new_name, factor)) = humanized_metric(&column, extra_percent_metrics) else {
+            continue;
+        };
+
+        if df.get_column_index(&new_name).is_some() {
+            log::warn!("Not humanizing {column}, since column {new_name} already exists");
+            continue;
+        }
+
+        let idx = df.try_get_column_index(&column)?;
+        let mut converted = &df.get_columns()[idx].cast(&DataType::Float64)? * factor;
+        converted.rename(&new_name);
+        df.replace_column(idx, converted)?;
+    }
+
+    Ok(())
+}
+
+/// Name and conversion factor `humanize_metrics` uses for a metric column, if it converts it
+pub fn humanized_metric(column: &str, extra_percent_metrics: &[String]) -> Option<(String, f64)> {
+    let metric = column.strip_prefix("metrics.")?;
+    if let Some(base) = metric.strip_suffix("_micros") {
+        Some((format!("metrics.{base}"), 1e-6))
+    } else if GOOGLE_ADS_METRICS_PERCENT_FIELDS.contains(&metric)
+        || extra_percent_metrics.iter().any(|m| m == metric)
+    {
+        Some((format!("metrics.{metric}_pct"), 100.0))
+    } else {
+        None
+    }
+}
+
+/// Format metric columns as readable text for table display, leaving other columns as is:
+/// thousand-separated integers, `*_micros` as currency units with 2 decimals,
+/// and fractional rates as percentages. Used for terminal output only, never for files.
+pub fn humanize_for_display(df: &DataFrame, extra_percent_metrics: &[String]) -> Result<DataFrame> {
+    let mut columns: Vec<Series> = Vec::with_capacity(df.width());
+
+    for series in df.get_columns() {
+        let name = series.name();
+        let Some(metric) = name.strip_prefix("metrics.") else {
+            columns.push(series.clone());
+            continue;
+        };
+        if !series.dtype().is_numeric() {
+            columns.push(series.clone());
+            continue;
+        }
+
+        let (new_name, format): (String, fn(f64) -> String) =
+            if let Some(base) = metric.strip_suffix("_micros") {
+                (format!("metrics.{base}"), |v| format!("{:.2}", v / 1e6).separate_with_commas())
+            } else if GOOGLE_ADS_METRICS_PERCENT_FIELDS.contains(&metric)
+                || extra_percent_metrics.iter().any(|m| m == metric)
+            {
+                (name.to_owned(), |v| format!("{:.2}%", v * 100.0))
+            } else if series.dtype().is_integer() {
+                (name.to_owned(), |v| format!("{v:.0}").separate_with_commas())
+            } else {
+                (name.to_owned(), |v| format!("{v:.2}").separate_with_commas())
+            };
+
+        let values: Vec<Option<String>> = series
+            .cast(&DataType::Float64)?
+            .f64()?
+            .into_iter()
+            .map(|v| v.map(format))
+            .collect();
+        columns.push(Series::new(&new_name, values));
+    }
+
+    Ok(DataFrame::new(columns)?)
+}
+
+/// Field metadata from GoogleAdsFieldService, as kept in the field cache
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldMetadata {
+    /// e.g. Attribute, Segment, Metric
+    pub category: String,
+    /// e.g. Date, String, Int64
+    pub data_type: String,
+    pub filterable: bool,
+}
+
+/// Field metadata by field name, e.g. `segments.date`
+pub type FieldCache = HashMap<String, FieldMetadata>;
+
+/// field metadata only changes between API versions
+fn field_cache_path() -> Option<PathBuf> {
+    crate::config::cache_file_path(&format!("fields-{API_VERSION}.json"))
+}
+
+/// Field metadata cached by previous runs, without calling the API. Empty if nothing is cached.
+pub fn read_field_cache() -> FieldCache {
+    let Some(path) = field_cache_path() else {
+        return FieldCache::new();
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+            log::warn!("Ignoring invalid field cache {}: {e}", path.display());
+            FieldCache::new()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => FieldCache::new(),
+        Err(e) => {
+            log::warn!("Unable to read field cache {}: {e}", path.display());
+            FieldCache::new()
+        }
+    }
+}
+
+/// Field metadata for `fields`, looking up fields not yet cached via GoogleAdsFieldService and caching them.
+/// Names the service does not know, e.g. added columns, are left out.
+pub async fn load_field_cache(api_context: GoogleAdsAPIAccess, fields: &[String]) -> Result<FieldCache> {
+    let mut field_cache = read_field_cache();
+
+    let mut missing: Vec<&String> = fields
+        .iter()
+        .filter(|field| is_field_name(field) && !field_cache.contains_key(*field))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    if missing.is_empty() {
+        return Ok(field_cache);
+    }
+
+    let names: Vec<String> = missing.iter().map(|field| format!("'{field}'")).collect();
+    let query = format!("SELECT name, category, data_type, filterable WHERE name IN ({})", names.join(", "));
+    log::debug!("Looking up metadata of {} fields", missing.len());
+
+    let mut client =
+        GoogleAdsFieldServiceClient::with_interceptor(api_context.channel.clone(), api_context);
+    let mut page_token = String::new();
+
+    loop {
+        let response: SearchGoogleAdsFieldsResponse = client
+            .search_google_ads_fields(SearchGoogleAdsFieldsRequest {
+                query: query.clone(),
+                page_token,
+                page_size: missing.len() as i32,
+            })
+            .await?
+            .into_inner();
+
+        for row in response.results {
+            let metadata = FieldMetadata {
+                category: format!("{:?}", row.category()),
+                data_type: format!("{:?}", row.data_type()),
+                filterable: row.filterable,
+            };
+            field_cache.insert(row.name, metadata);
+        }
+
+        if response.next_page_token.is_empty() {
+            break;
+        }
+        page_token = response.next_page_token;
+    }
+
+    // a cache that can't be saved is only a missed optimization
+    if let Err(e) = write_field_cache(&field_cache) {
+        log::warn!("Unable to save field cache: {e}");
+    }
+
+    Ok(field_cache)
+}
+
+fn write_field_cache(field_cache: &FieldCache) -> Result<()> {
+    let Some(path) = field_cache_path() else {
+        bail!("Unable to locate cache dir for field cache");
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_json::to_string(field_cache)?)?;
+
+    log::debug!("Cached metadata of {} fields in {}", field_cache.len(), path.display());
+
+    Ok(())
+}
+
+/// field names are interpolated into FieldService queries, so only field name characters are accepted
+fn is_field_name(field: &str) -> bool {
+    !field.is_empty() && field.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '.')
+}
+
+/// Temporal data type of a field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TemporalKind {
+    Date,
+    DateTime,
+}
+
+/// Whether a field is a DATE or DATETIME, from its data type in the field cache, or inferred from its name
+/// when not cached, e.g. `segments.date`, `campaign.start_date`, `change_event.change_date_time`.
+/// GoogleAdsFieldService reports DATETIME fields as DATE, so those are told apart by their `date_time` suffix.
+pub fn temporal_kind(field: &str, field_cache: &FieldCache) -> Option<TemporalKind> {
+    let is_temporal = match field_cache.get(field) {
+        Some(metadata) => metadata.data_type == format!("{:?}", GoogleAdsFieldDataType::Date),
+        None => {
+            !field.starts_with("metrics.")
+                && (field.ends_with("date_time")
+                    || field.ends_with(".date")
+                    || field.ends_with("_date")
+                    || GOOGLE_ADS_DATE_FIELDS.contains(&field))
         }
     };
 
-    Ok((df, total_api_consumption))
+    if !is_temporal {
+        None
+    } else if field.ends_with("date_time") {
+        Some(TemporalKind::DateTime)
+    } else {
+        Some(TemporalKind::Date)
+    }
+}
+
+/// Check a field can take a date window filter: filterable, and DATE or DATETIME per the field cache
+pub fn check_date_field(field: &str, field_cache: &FieldCache) -> Result<()> {
+    let Some(metadata) = field_cache.get(field) else {
+        bail!("{field} is not a known field");
+    };
+    if temporal_kind(field, field_cache).is_none() {
+        bail!("{field} is not a DATE or DATETIME field, its data type is {}", metadata.data_type);
+    }
+    if !metadata.filterable {
+        bail!("{field} is not filterable");
+    }
+
+    Ok(())
+}
+
+/// Parse DATE and DATETIME columns, identified by `temporal_kind`, into polars temporal types,
+/// so they sort and filter chronologically. DATETIMEs with a UTC offset are converted to UTC.
+/// Empty values become null. Columns with any unparseable value are left as strings.
+pub fn parse_temporal_columns(df: &mut DataFrame, field_cache: &FieldCache) -> Result<()> {
+    let columns: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| !c.starts_with("metrics."))
+        .map(str::to_owned)
+        .collect();
+
+    for column in columns {
+        let Some(kind) = temporal_kind(&column, field_cache) else {
+            continue;
+        };
+
+        let idx = df.try_get_column_index(&column)?;
+        let Ok(values) = df.get_columns()[idx].str() else {
+            continue;
+        };
+
+        let parsed = if kind == TemporalKind::Date {
+            parse_column_values(values, |s| NaiveDate::parse_from_str(s, crate::util::DATE_FORMAT).ok())
+                .map(|v| DateChunked::from_naive_date_options(&column, v).into_series())
+        } else {
+            parse_column_values(values, parse_datetime).map(|v| {
+                DatetimeChunked::from_naive_datetime_options(&column, v, TimeUnit::Microseconds)
+                    .into_series()
+            })
+        };
+
+        match parsed {
+            Some(series) => {
+                df.replace_column(idx, series)?;
+            }
+            None => {
+                log::warn!("Leaving {column} as string, since some values are not valid dates");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// parse non-empty values, or None if any fails to parse
+fn parse_column_values<T>(values: &StringChunked, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<Option<T>>> {
+    values
+        .into_iter()
+        .map(|v| match v.map(str::trim) {
+            None | Some("") => Some(None),
+            Some(s) => parse(s).map(Some),
+        })
+        .collect()
+}
+
+fn parse_datetime(s: &str) -> Option<NaiveDateTime> {
+    DATETIME_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(s, format).ok())
+        .or_else(|| {
+            DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f%:z")
+                .or_else(|_| DateTime::parse_from_rfc3339(s))
+                .ok()
+                .map(|dt| dt.naive_utc())
+        })
+}
+
+/// Classify a query error by its gRPC status code, for run summaries
+pub fn error_kind(e: &anyhow::Error) -> String {
+    match e.downcast_ref::<Status>() {
+        Some(status) => format!("{:?}", status.code()),
+        None => "Other".to_owned(),
+    }
 }
 
 /// Run query via GoogleAdsServiceClient to get performance data
@@ -295,42 +1070,156 @@ pub async fn gaql_query(
     customer_id: String,
     query: String,
 ) -> Result<(DataFrame, i64)> {
-    let client: GoogleAdsServiceClient<InterceptedService<Channel, GoogleAdsAPIAccess>> =
-        GoogleAdsServiceClient::with_interceptor(api_context.channel.clone(), api_context);
+    let client = api_context.client();
 
-    gaql_query_with_client(client, customer_id, query).await
+    let (df, api_consumption, _) =
+        gaql_query_with_client(client, customer_id, query, SummaryRowSetting::NoSummaryRow, None, None).await?;
+
+    Ok((df, api_consumption))
+}
+
+/// Check a query with the live API via a validate-only search against one account, without fetching data
+pub async fn validate_query(api_context: GoogleAdsAPIAccess, customer_id: &str, query: &str) -> Result<()> {
+    let mut client = api_context.client();
+
+    // search_stream has no validate-only option, so use paged search
+    let result = client
+        .search(SearchGoogleAdsRequest {
+            customer_id: customer_id.to_owned(),
+            query: query.to_owned(),
+            validate_only: true,
+            ..Default::default()
+        })
+        .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(status) => bail!("{:?}: {}", status.code(), status.message()),
+    }
 }
 
-/// Run query via GoogleAdsFieldService to obtain field metadata
-pub async fn fields_query(api_context: GoogleAdsAPIAccess, query: &str) {
+/// Look up valid values of an ENUM field via GoogleAdsFieldService,
+/// falling back to a curated list for common fields when the service returns none.
+pub async fn enum_values(api_context: GoogleAdsAPIAccess, field: &str) -> Result<Vec<String>> {
+    if !is_field_name(field) {
+        bail!("Invalid field name '{field}', expected e.g. campaign.status");
+    }
+
     let mut client =
         GoogleAdsFieldServiceClient::with_interceptor(api_context.channel.clone(), api_context);
 
     let response: SearchGoogleAdsFieldsResponse = client
         .search_google_ads_fields(SearchGoogleAdsFieldsRequest {
-            query: query.to_owned(),
+            query: format!("SELECT name, data_type, enum_values WHERE name = '{field}'"),
             page_token: String::new(),
-            page_size: 10000,
+            page_size: 1,
         })
-        .await
-        .unwrap()
+        .await?
         .into_inner();
 
-    let mut stdout = async_std::io::stdout();
-    for r in response.results {
-        let row: GoogleAdsField = r;
-        let val = format!("{}\t{:?}\t{:?}\t{}\n", row.name, row.data_type(), row.category(), row.resource_name);
-        stdout.write_all(val.as_bytes()).await.unwrap();
+    let known_values = || {
+        KNOWN_ENUM_VALUES
+            .iter()
+            .find(|(name, _)| *name == field)
+            .map(|(_, values)| values.iter().map(|v| v.to_string()).collect())
+            .unwrap_or_default()
+    };
+
+    let Some(row) = response.results.into_iter().next() else {
+        log::debug!("Field {field} not found in GoogleAdsFieldService");
+        return Ok(known_values());
+    };
+
+    if row.data_type() != GoogleAdsFieldDataType::Enum {
+        bail!("{field} is not an ENUM field, its data type is {:?}", row.data_type());
+    }
+
+    if row.enum_values.is_empty() {
+        return Ok(known_values());
     }
+
+    Ok(row.enum_values)
 }
 
-pub async fn get_child_account_ids(
+/// Run query via GoogleAdsFieldService to obtain field metadata, including each field's resource,
+/// capabilities (selectable, filterable, sortable) and enum values.
+///
+/// Results are fetched `page_size` fields at a time, following page tokens until exhausted.
+/// Smaller pages lower peak memory per response at the cost of more round trips.
+pub async fn fields_query(
     api_context: GoogleAdsAPIAccess,
+    query: &str,
+    page_size: i32,
+) -> Result<DataFrame> {
+    let mut client =
+        GoogleAdsFieldServiceClient::with_interceptor(api_context.channel.clone(), api_context);
+
+    let mut names: Vec<String> = Vec::new();
+    let mut resources: Vec<String> = Vec::new();
+    let mut categories: Vec<String> = Vec::new();
+    let mut data_types: Vec<String> = Vec::new();
+    let mut selectable: Vec<bool> = Vec::new();
+    let mut filterable: Vec<bool> = Vec::new();
+    let mut sortable: Vec<bool> = Vec::new();
+    let mut is_repeated: Vec<bool> = Vec::new();
+    let mut enum_values: Vec<String> = Vec::new();
+    let mut resource_names: Vec<String> = Vec::new();
+
+    let mut page_token = String::new();
+
+    loop {
+        let response: SearchGoogleAdsFieldsResponse = client
+            .search_google_ads_fields(SearchGoogleAdsFieldsRequest {
+                query: query.to_owned(),
+                page_token,
+                page_size,
+            })
+            .await?
+            .into_inner();
+
+        for r in response.results {
+            let row: GoogleAdsField = r;
+            // fields are named <resource>.<attribute>, or metrics.* / segments.*
+            let resource = row.name.split('.').next().unwrap_or_default().to_owned();
+
+            resources.push(resource);
+            categories.push(format!("{:?}", row.category()));
+            data_types.push(format!("{:?}", row.data_type()));
+            selectable.push(row.selectable);
+            filterable.push(row.filterable);
+            sortable.push(row.sortable);
+            is_repeated.push(row.is_repeated);
+            enum_values.push(row.enum_values.join(","));
+            resource_names.push(row.resource_name);
+            names.push(row.name);
+        }
+
+        if response.next_page_token.is_empty() {
+            break;
+        }
+        page_token = response.next_page_token;
+    }
+
+    let df = DataFrame::new(vec![
+        Series::new("name", names),
+        Series::new("resource", resources),
+        Series::new("category", categories),
+        Series::new("data_type", data_types),
+        Series::new("selectable", selectable),
+        Series::new("filterable", filterable),
+        Series::new("sortable", sortable),
+        Series::new("is_repeated", is_repeated),
+        Series::new("enum_values", enum_values),
+        Series::new("resource_name", resource_names),
+    ])?;
+
+    Ok(df)
+}
+
+pub async fn get_child_account_ids(
+    mut client: GoogleAdsClient,
     mcc_customer_id: String,
 ) -> Result<Vec<String>> {
-    let mut client: GoogleAdsServiceClient<InterceptedService<Channel, GoogleAdsAPIAccess>> =
-        GoogleAdsServiceClient::with_interceptor(api_context.channel.clone(), api_context);
-
     let result: Result<Response<Streaming<SearchGoogleAdsStreamResponse>>, Status> = client
         .search_stream(SearchGoogleAdsStreamRequest {
             customer_id: mcc_customer_id.clone(),
@@ -379,3 +1268,243 @@ pub async fn get_child_account_ids(
 
     Ok(customer_ids.unwrap())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_metadata(category: &str, data_type: &str) -> FieldMetadata {
+        FieldMetadata {
+            category: category.to_owned(),
+            data_type: data_type.to_owned(),
+            filterable: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_token_cache_is_token_cache_error() {
+        let path = std::env::temp_dir().join(format!("mcc-gaql-test-{}-tokencache.json", std::process::id()));
+        fs::write(&path, "[{\"scopes\": [").unwrap();
+
+        let build_error = InstalledFlowAuthenticator::builder(
+            ApplicationSecret::default(),
+            InstalledFlowReturnMethod::HTTPRedirect,
+        )
+        .persist_tokens_to_disk(&path)
+        .build()
+        .await
+        .err();
+        let result = build_authenticator(ApplicationSecret::default(), &path).await;
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(build_error.map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+        let err = result.err().expect("corrupt token cache should fail");
+        assert!(err.is::<TokenCacheError>(), "{err}");
+    }
+
+    #[test]
+    fn check_date_field_uses_metadata() {
+        let mut unfilterable = field_metadata("Attribute", "Date");
+        unfilterable.filterable = false;
+        let field_cache = FieldCache::from([
+            ("campaign.start_date".to_owned(), field_metadata("Attribute", "Date")),
+            ("change_event.change_date_time".to_owned(), field_metadata("Attribute", "Date")),
+            ("ad_group.label_date".to_owned(), field_metadata("Attribute", "String")),
+            ("campaign.end_date".to_owned(), unfilterable),
+        ]);
+
+        assert!(check_date_field("campaign.start_date", &field_cache).is_ok());
+        assert!(check_date_field("change_event.change_date_time", &field_cache).is_ok());
+        assert!(check_date_field("ad_group.label_date", &field_cache).is_err());
+        assert!(check_date_field("campaign.end_date", &field_cache).is_err());
+        assert!(check_date_field("campaign.unknown_date", &field_cache).is_err());
+    }
+
+    #[test]
+    fn temporal_kind_falls_back_to_name() {
+        let field_cache = FieldCache::from([("campaign.start_date".to_owned(), field_metadata("Attribute", "String"))]);
+
+        assert_eq!(temporal_kind("campaign.start_date", &field_cache), None);
+        assert_eq!(temporal_kind("campaign.end_date", &field_cache), Some(TemporalKind::Date));
+        assert_eq!(temporal_kind("segments.week", &field_cache), Some(TemporalKind::Date));
+        assert_eq!(
+            temporal_kind("change_event.change_date_time", &field_cache),
+            Some(TemporalKind::DateTime)
+        );
+        assert_eq!(temporal_kind("metrics.last_date", &field_cache), None);
+    }
+
+    #[test]
+    fn parse_temporal_columns_date() {
+        let mut df = df!(
+            "segments.date" => ["2024-01-02", "", "2023-12-31"],
+            "metrics.clicks" => ["1", "2", "3"]
+        )
+        .unwrap();
+
+        parse_temporal_columns(&mut df, &FieldCache::new()).unwrap();
+
+        let dates = df.column("segments.date").unwrap();
+        assert_eq!(dates.dtype(), &DataType::Date);
+        let expected = Series::new(
+            "segments.date",
+            [NaiveDate::from_ymd_opt(2024, 1, 2), None, NaiveDate::from_ymd_opt(2023, 12, 31)],
+        );
+        assert!(dates.equals_missing(&expected));
+        assert_eq!(df.column("metrics.clicks").unwrap().dtype(), &DataType::String);
+    }
+
+    #[test]
+    fn parse_temporal_columns_datetime() {
+        let mut df = df!(
+            "change_event.change_date_time" => ["2024-01-02 03:04:05", "2024-01-02 03:04:05.5+02:00"]
+        )
+        .unwrap();
+
+        parse_temporal_columns(&mut df, &FieldCache::new()).unwrap();
+
+        let values = df.column("change_event.change_date_time").unwrap();
+        assert_eq!(values.dtype(), &DataType::Datetime(TimeUnit::Microseconds, None));
+        let expected: Vec<Option<i64>> = vec![
+            Some(parse_datetime("2024-01-02 03:04:05").unwrap().and_utc().timestamp_micros()),
+            Some(parse_datetime("2024-01-02 01:04:05.5").unwrap().and_utc().timestamp_micros()),
+        ];
+        assert_eq!(values.datetime().unwrap().into_iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn parse_temporal_columns_prefers_field_cache() {
+        let mut df = df!(
+            "ad_group.label_date" => ["2024-01-02"],
+            "campaign.end" => ["2024-01-03"]
+        )
+        .unwrap();
+        let field_cache = FieldCache::from([
+            ("ad_group.label_date".to_owned(), field_metadata("Attribute", "String")),
+            ("campaign.end".to_owned(), field_metadata("Attribute", "Date")),
+        ]);
+
+        parse_temporal_columns(&mut df, &field_cache).unwrap();
+
+        assert_eq!(df.column("ad_group.label_date").unwrap().dtype(), &DataType::String);
+        assert_eq!(df.column("campaign.end").unwrap().dtype(), &DataType::Date);
+    }
+
+    #[test]
+    fn parse_temporal_columns_keeps_unparseable_as_string() {
+        let mut df = df!("segments.date" => ["2024-01-02", "not a date"]).unwrap();
+
+        parse_temporal_columns(&mut df, &FieldCache::new()).unwrap();
+
+        assert_eq!(df.column("segments.date").unwrap().dtype(), &DataType::String);
+    }
+
+    #[test]
+    fn add_resource_names_from_ids() {
+        let mut df = df!(
+            "customer.id" => ["1111111111"],
+            "campaign.id" => ["22"],
+            "campaign.name" => ["Brand"],
+            "ad_group.id" => ["33"],
+            "ad_group.resource_name" => ["customers/1111111111/adGroups/33"],
+            "ad_group_ad.ad.id" => ["44"]
+        )
+        .unwrap();
+
+        add_resource_names(&mut df, "1111111111").unwrap();
+
+        assert_eq!(
+            df.get_column_names(),
+            [
+                "customer.id",
+                "customer.resource_name",
+                "campaign.id",
+                "campaign.resource_name",
+                "campaign.name",
+                "ad_group.id",
+                "ad_group.resource_name",
+                "ad_group_ad.ad.id",
+            ]
+        );
+        let value = |column: &str| df.column(column).unwrap().str().unwrap().get(0).unwrap().to_owned();
+        assert_eq!(value("customer.resource_name"), "customers/1111111111");
+        assert_eq!(value("campaign.resource_name"), "customers/1111111111/campaigns/22");
+        assert_eq!(value("ad_group.resource_name"), "customers/1111111111/adGroups/33");
+    }
+
+    #[test]
+    fn find_duplicate_columns() {
+        let columns = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(find_duplicate(&columns(&[])), None);
+        assert_eq!(find_duplicate(&columns(&["campaign.id", "metrics.clicks"])), None);
+        assert_eq!(
+            find_duplicate(&columns(&["campaign.id", "metrics.clicks", "metrics.clicks", "campaign.id"])),
+            Some(&"metrics.clicks".to_owned())
+        );
+    }
+
+    #[test]
+    fn response_without_field_mask_is_empty() {
+        let requested = vec!["campaign.id".to_owned(), "metrics.clicks".to_owned()];
+        let mut response_columns = ResponseColumns::default();
+
+        assert!(!response_columns.use_field_mask("1111111111", &requested, None).unwrap());
+        response_columns.push_summary_row(&GoogleAdsRow::default());
+
+        let df = response_columns.into_dataframe().unwrap();
+        assert_eq!(df.shape(), (0, 0));
+    }
+
+    #[test]
+    fn response_with_field_mask_and_no_rows_has_typed_columns() {
+        let requested = vec!["campaign.id".to_owned(), "metrics.clicks".to_owned()];
+        let mut response_columns = ResponseColumns::default();
+
+        assert!(response_columns.use_field_mask("1111111111", &requested, Some(&requested)).unwrap());
+        // later chunks without a field mask keep the headers
+        assert!(response_columns.use_field_mask("1111111111", &requested, None).unwrap());
+
+        let df = response_columns.into_dataframe().unwrap();
+        assert_eq!(df.get_column_names(), ["campaign.id", "metrics.clicks"]);
+        assert_eq!(df.height(), 0);
+        assert_eq!(df.column("metrics.clicks").unwrap().dtype(), &DataType::Int64);
+    }
+
+    #[test]
+    fn response_metric_values_parse_to_numbers_or_null() {
+        let response_columns = ResponseColumns {
+            headers: Some(vec!["campaign.id".to_owned(), "metrics.ctr".to_owned()]),
+            columns: vec![
+                vec!["1".to_owned(), String::new()],
+                vec!["0.25".to_owned(), String::new()],
+            ],
+        };
+
+        let df = response_columns.into_dataframe().unwrap();
+        let ctr = df.column("metrics.ctr").unwrap().f64().unwrap();
+        assert_eq!(ctr.get(0), Some(0.25));
+        assert_eq!(ctr.get(1), None);
+    }
+
+    #[test]
+    fn response_with_non_numeric_metric_fails() {
+        let response_columns = ResponseColumns {
+            headers: Some(vec!["metrics.historical_creative_quality_score".to_owned()]),
+            columns: vec![vec!["ABOVE_AVERAGE".to_owned()]],
+        };
+
+        let err = response_columns.into_dataframe().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid value 'ABOVE_AVERAGE' in metric column 'metrics.historical_creative_quality_score'"
+        );
+    }
+
+    #[test]
+    fn response_with_duplicate_columns_fails() {
+        let paths = vec!["campaign.id".to_owned(), "campaign.id".to_owned()];
+
+        assert!(ResponseColumns::default().use_field_mask("1111111111", &paths, Some(&paths)).is_err());
+    }
+}
@@ -3,81 +3,346 @@
 // 
 
 use std::{
-    fs::{self, File},
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
     process,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Days, Local, NaiveDate, SecondsFormat, Utc};
 use futures::{stream::FuturesUnordered, StreamExt};
 
-use googleads::GoogleAdsAPIAccess;
-use googleads_rs::google::ads::googleads::v18::services::google_ads_service_client::GoogleAdsServiceClient;
+use googleads::{GoogleAdsAPIAccess, GoogleAdsClient};
+use googleads_rs::google::ads::googleads::v18::enums::summary_row_setting_enum::SummaryRowSetting;
+use encoding_rs::{Encoding, UTF_8};
+use polars::lazy::frame::pivot::pivot_stable;
 use polars::prelude::*;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use serde::Serialize;
 use thousands::Separable;
-use tonic::{codegen::InterceptedService, transport::Channel};
 
 mod args;
 mod config;
+mod expr;
 mod googleads;
+#[cfg(feature = "sheets")]
+mod sheets;
 mod util;
 
+/// How long to wait for in-flight queries after Ctrl-C before writing partial results
+const INTERRUPT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// exit code when `--timeout-overall` cuts a run short, as used by `timeout(1)`
+const EXIT_CODE_TIMEOUT: i32 = 124;
+// rows printed to a terminal before asking for confirmation, unless set in config
+const DEFAULT_MAX_TABLE_ROWS: usize = 1000;
+// warn when `--split-by` would write more files than this
+const SPLIT_BY_WARN_FILES: usize = 100;
+
+/// column added by `--account-labels`
+const ACCOUNT_LABEL_COLUMN: &str = "customer_name";
+/// column added by `--count-accounts-per-group`
+const ACCOUNT_COUNT_COLUMN: &str = "account_count";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     util::init_logger();
 
     let mut args = args::parse();
 
-    let profile = &args.profile.unwrap_or_else(|| "test".to_owned());
+    let profile = args.profile.as_deref().unwrap_or("test");
 
-    let config = config::load(profile).context(format!("Loading config for profile: {profile}"))?;
+    let config = config::load(profile, args.config.as_deref().map(Path::new)).context(format!("Loading config for profile: {profile}"))?;
 
     log::debug!("Configuration: {config:?}");
 
+    if let Some(format) = &args.dump_resolved_config {
+        let dumped = match format.as_str() {
+            "json" => serde_json::to_string_pretty(&config).map_err(anyhow::Error::from),
+            _ => toml::to_string(&config).map_err(anyhow::Error::from),
+        };
+        match dumped {
+            Ok(s) => {
+                println!("{}", s.trim_end());
+                return Ok(());
+            }
+            Err(e) => util::fail(format!("Unable to serialize config for profile {profile}: {e}")),
+        }
+    }
+
     // load stored query
-    if let Some(query_name) = args.stored_query {
-        if let Some(query_filename) = config.queries_filename {
-            let queries_path = crate::config::config_file_path(&query_filename).unwrap();
+    if let Some(query_name) = &args.stored_query {
+        if let Some(query_filename) = &config.queries_filename {
+            let queries_path = crate::config::config_file_path(query_filename).unwrap();
 
-            args.gaql_query = match util::get_query_from_file(queries_path, &query_name).await {
+            args.gaql_query = match util::get_query_from_file(queries_path, query_name).await {
                 Ok(s) => Some(s),
-                Err(e) => {
-                    let msg = format!("Unable to load query: {e}");
-                    log::error!("{msg}");
-                    println!("{msg}");
-                    process::exit(1);
+                Err(e) => util::fail(format!("Unable to load query: {e}")),
+            }
+        }
+    }
+
+    // fall back to profile's default query when no other work is requested
+    if args.gaql_query.is_none()
+        && args.stored_query.is_none()
+        && !args.list_child_accounts
+        && !args.check_quota
+        && args.enum_values.is_none()
+        && !args.changes
+        && !args.field_service
+    {
+        if let Some(query) = &config.default_query {
+            log::info!("Running default query for profile {profile}");
+            args.gaql_query = Some(query.clone());
+        }
+    }
+
+    // substitute query parameters, leaving a chunked IN-list param to fill in per chunk
+    let mut param_chunks: Vec<String> = Vec::new();
+    if let Some(query) = &args.gaql_query {
+        if !args.param.is_empty() {
+            let substituted = match &args.chunk_param {
+                Some(name) => {
+                    let prefix = format!("{name}=");
+                    let (chunked, others): (Vec<String>, Vec<String>) =
+                        args.param.iter().cloned().partition(|p| p.starts_with(&prefix));
+                    param_chunks = chunked
+                        .first()
+                        .map(|p| util::chunk_param_values(&p[prefix.len()..], args.chunk_size))
+                        .unwrap_or_default();
+                    util::apply_query_params_deferring(query, &others, name)
                 }
+                None => util::apply_query_params(query, &args.param),
+            };
+            args.gaql_query = match substituted {
+                Ok(s) => Some(s),
+                Err(e) => util::fail(format!("Unable to apply query parameters: {e}")),
+            }
+        }
+    }
+
+    // date window from --since/--until, which may be relative to today
+    let today = Local::now().date_naive();
+    let parse_window_date = |date: &Option<String>| match date
+        .as_deref()
+        .map(|s| util::parse_relative_date(s, today))
+        .transpose()
+    {
+        Ok(date) => date,
+        Err(e) => util::fail(format!("Invalid date window: {e}")),
+    };
+    let since = parse_window_date(&args.since);
+    let until = parse_window_date(&args.until);
+    // date window conditions added to the query, also added to --query-map queries
+    let mut date_conditions: Vec<String> = Vec::new();
+    if let (Some(since), Some(until)) = (since, until) {
+        if since > until {
+            util::fail(format!("--since {since} is after --until {until}"));
+        }
+    }
+
+    if args.changes {
+        let since = since.unwrap_or(today - Days::new(7));
+        let until = until.unwrap_or(today);
+        args.gaql_query = match googleads::change_event_query(since, until, today, args.changes_limit) {
+            Ok(query) => Some(query),
+            Err(e) => util::fail(format!("Unable to build change query: {e}")),
+        };
+    } else if since.is_some() || until.is_some() {
+        if let Some(query) = &args.gaql_query {
+            let date_field_kind = date_field_kind(&args.date_field);
+            if let Some(since) = since {
+                date_conditions.push(date_condition(&args.date_field, date_field_kind, ">=", since));
+            }
+            if let Some(until) = until {
+                date_conditions.push(date_condition(&args.date_field, date_field_kind, "<=", until));
             }
+            let query = date_conditions
+                .iter()
+                .fold(query.clone(), |query, condition| util::add_where_condition(&query, condition));
+            args.gaql_query = Some(query);
+        }
+    }
+
+    // only fetch data since last successful run, keyed by the query before date filtering
+    let mut last_run_key: Option<String> = None;
+    if args.since_last_run {
+        if let Some(query) = &args.gaql_query {
+            let since = match util::read_last_run_date(profile, query) {
+                Ok(Some(date)) => date,
+                Ok(None) => match args.initial_since.as_deref().map(|s| util::parse_relative_date(s, today)) {
+                    Some(Ok(date)) => date,
+                    Some(Err(e)) => util::fail(format!("Invalid --initial-since: {e}")),
+                    None => util::fail("No previous run recorded for this query. Use --initial-since to set the start date."),
+                },
+                Err(e) => util::fail(format!("Unable to load last run date: {e}")),
+            };
+            log::info!("Fetching data since {since}");
+
+            last_run_key = Some(query.clone());
+            let condition = date_condition(&args.date_field, date_field_kind(&args.date_field), ">=", since);
+            args.gaql_query = Some(util::add_where_condition(query, &condition));
+            date_conditions.push(condition);
         }
     }
 
     // for non-FieldService queries, reduce network traffic by excluding resource_name by default
     if let Some(query) = &args.gaql_query {
-        if !args.field_service && !query.contains("PARAMETERS") {
-            let new_query = format!("{query} PARAMETERS omit_unselected_resource_names = true");
+        if !args.field_service {
+            let new_query = util::add_parameter(query, "omit_unselected_resource_names", "true");
             args.gaql_query = Some(new_query);
         }
     }
 
+    if let Some(name) = args.chunk_param.as_deref().filter(|_| param_chunks.is_empty()) {
+        util::fail(format!("--chunk-param {name} requires a --param {name}=<comma-separated values>"));
+    }
+
+    // one query per chunk of the chunked IN-list, with the first standing in for the query elsewhere
+    let mut chunk_queries: Vec<String> = Vec::new();
+    if let (Some(name), Some(query)) = (&args.chunk_param, &args.gaql_query) {
+        chunk_queries = match param_chunks
+            .iter()
+            .map(|chunk| util::apply_query_params(query, &[format!("{name}={chunk}")]))
+            .collect::<Result<Vec<_>>>()
+        {
+            Ok(queries) => queries,
+            Err(e) => util::fail(format!("Unable to apply --chunk-param {name}: {e}")),
+        };
+        log::info!(
+            "Running query in {} chunks of up to {} {name} values",
+            chunk_queries.len().separate_with_commas(),
+            args.chunk_size.separate_with_commas()
+        );
+        args.gaql_query = chunk_queries.first().cloned();
+    }
+
+    // guard against oversized queries, e.g. from long IN-lists, before they fail cryptically at the API
+    if let Some(query_length) = chunk_queries.iter().chain(&args.gaql_query).map(String::len).max() {
+        let max_query_length = config
+            .max_query_length
+            .unwrap_or(googleads::DEFAULT_MAX_QUERY_LENGTH);
+        log::debug!("Final query length: {} chars", query_length);
+
+        if query_length > max_query_length {
+            util::fail(format!(
+                "Query length of {} chars exceeds max of {} chars. If it has a long IN-list, use --chunk-param to run it in smaller batches.",
+                query_length.separate_with_commas(),
+                max_query_length.separate_with_commas()
+            ));
+        }
+    }
+
+    let query_map = match args.query_map.as_deref().map(|path| {
+        load_query_map(path, &args, &config, &date_conditions).context("Invalid --query-map")
+    }) {
+        Some(Ok(queries)) => queries,
+        Some(Err(e)) => util::fail(format!("{e:#}")),
+        None => HashMap::new(),
+    };
+
+    // validate column type overrides before spending time on auth and queries
+    let casts = match args.cast.iter().map(|spec| parse_cast(spec)).collect::<Result<Vec<_>>>() {
+        Ok(casts) => casts,
+        Err(e) => util::fail(format!("Invalid --cast: {e}")),
+    };
+
+    let aggs = match args.agg.iter().map(|spec| parse_agg(spec)).collect::<Result<Vec<_>>>() {
+        Ok(aggs) => aggs,
+        Err(e) => util::fail(format!("Invalid --agg: {e}")),
+    };
+
+    let account_labels = match args.account_labels.as_deref().map(util::get_account_labels_from_file) {
+        Some(Ok(labels)) => Some(labels),
+        Some(Err(e)) => util::fail(format!("Invalid --account-labels: {e}")),
+        None => None,
+    };
+
+    let rename_map = match args.rename_map.as_deref().map(util::get_rename_map_from_file) {
+        Some(Ok(renames)) => renames,
+        Some(Err(e)) => util::fail(format!("Invalid --rename-map: {e}")),
+        None => Vec::new(),
+    };
+
+    let output_encoding = match output_encoding(&args.encoding, &args.encoding_placeholder) {
+        Ok(encoding) => encoding,
+        Err(e) => util::fail(format!("Invalid --encoding: {e}")),
+    };
+
+    let merge_with = match &args.merge_with {
+        Some(path) => match load_local_dataset(path, &args.on) {
+            Ok(df) => Some((df, args.on.clone())),
+            Err(e) => util::fail(format!("Invalid --merge-with: {e}")),
+        },
+        None => None,
+    };
+
+    // catch typos in --groupby/--sortby before spending API calls on the query
+    if let Some(query) = args.gaql_query.as_deref().filter(|_| !args.field_service) {
+        let mut extra_columns: Vec<&str> = Vec::new();
+        if args.add_customer_id || args.compare_accounts.is_some() {
+            extra_columns.push(&args.customer_id_column);
+        }
+        if args.account_labels.is_some() {
+            extra_columns.push(ACCOUNT_LABEL_COLUMN);
+        }
+        if args.count_accounts_per_group {
+            extra_columns.push(ACCOUNT_COUNT_COLUMN);
+        }
+        if let Err(e) = check_groupby_columns(query, &args, &extra_columns) {
+            util::fail(e);
+        }
+    }
+
+    // parse post-processing steps up front so syntax errors don't waste a full run
+    let steps = match args.expr.iter().map(|step| expr::parse_step(step)).collect::<Result<Vec<_>>>() {
+        Ok(steps) => steps,
+        Err(e) => util::fail(format!("Invalid --expr: {e}")),
+    };
+
+    let auth_start = Instant::now();
     let api_context =
-        match googleads::get_api_access(&config.mcc_customerid, &config.token_cache_filename).await
+        match googleads::get_api_access(
+            &config.mcc_customerid,
+            &config.token_cache_filename,
+            config.tls_ca_cert_path.as_deref(),
+        )
+        .await
         {
             Ok(a) => a,
-            Err(_e) => {
-                log::info!(
-                    "Refresh token became invalid. Clearing token cache and forcing re-auth"
-                );
+            Err(e) if e.is::<googleads::ChannelError>() => {
+                // network or TLS failure, re-auth would not help
+                return Err(e.into());
+            }
+            Err(e) if e.is::<googleads::ClientSecretError>() => util::fail(e),
+            Err(e) => {
+                if e.is::<googleads::TokenCacheError>() {
+                    log::warn!("{e}. Removing token cache and forcing re-auth");
+                    eprintln!("{e}. Removing it and re-authenticating.");
+                } else {
+                    log::info!(
+                        "Refresh token became invalid. Clearing token cache and forcing re-auth"
+                    );
+                }
                 // remove cached token to force re-auth and try again
                 let token_cache_path =
                     crate::config::config_file_path(&config.token_cache_filename)
                         .expect("token cache path");
                 fs::remove_file(token_cache_path)
                     .expect("Failed to remove token cache file to force re-auth");
-                googleads::get_api_access(&config.mcc_customerid, &config.token_cache_filename)
-                    .await
-                    .expect("Refresh token expired and failed to kick off re-auth.")
+                googleads::get_api_access(
+                    &config.mcc_customerid,
+                    &config.token_cache_filename,
+                    config.tls_ca_cert_path.as_deref(),
+                )
+                .await
+                .expect("Refresh token expired and failed to kick off re-auth.")
             }
         };
+    let auth_duration = auth_start.elapsed();
 
     if args.list_child_accounts {
         // run Account listing query
@@ -105,105 +370,338 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 Err(e) => {
                     let msg = format!("Error: {e}");
-                    println!("{msg}");
+                    eprintln!("{msg}");
                     None
                 }
             };
 
         if dataframe.is_some() {
             if args.output.is_some() {
-                write_csv(&mut dataframe.unwrap(), args.output.as_ref().unwrap())?;
+                write_csv(
+                    &mut dataframe.unwrap(),
+                    args.output.as_ref().unwrap(),
+                    quote_style(&args.quote_style),
+                    output_encoding.as_ref(),
+                    None,
+                )?;
             } else {
                 println!("{}", dataframe.unwrap());
             }
         }
+    } else if args.check_quota {
+        // run cheap probe query against a single account
+        let customer_id = args.customer_id.unwrap_or(config.mcc_customerid);
+        log::debug!("Probing API quota with account {customer_id}");
+
+        let start = Instant::now();
+        let probe_query = googleads::QUOTA_PROBE_QUERY.to_owned();
+        match googleads::gaql_query(api_context, customer_id.clone(), probe_query).await {
+            Ok((_df, api_consumption)) => {
+                println!(
+                    "Probe query against account {customer_id} succeeded in {} msec using {} API units.",
+                    start.elapsed().as_millis().separate_with_commas(),
+                    api_consumption.separate_with_commas()
+                );
+                println!("Note: the API does not report remaining quota; daily limits depend on the developer token access level.");
+            }
+            Err(e) => {
+                let msg = format!("Probe query against account {customer_id} failed: {e}");
+                log::error!("{msg}");
+                eprintln!("{msg}");
+            }
+        }
+    } else if let Some(field) = &args.enum_values {
+        let values = googleads::enum_values(api_context, field).await?;
+        if values.is_empty() {
+            println!("No enum values found for {field}");
+        } else {
+            println!("{}", values.join("\n"));
+        }
     } else if args.field_service {
         let query = &args
             .gaql_query
             .expect("Valid Field Service query required.");
         log::info!("Running Fields Metadata query: {query}");
-        googleads::fields_query(api_context, query).await;
+        let mut dataframe =
+            googleads::fields_query(api_context, query, args.fields_page_size).await?;
+
+        if let Some(outfile) = &args.output {
+            write_csv(&mut dataframe, outfile, quote_style(&args.quote_style), output_encoding.as_ref(), None)?;
+        } else {
+            // print all rows as TSV, since table display truncates long field lists
+            CsvWriter::new(std::io::stdout())
+                .with_separator(b'\t')
+                .with_quote_style(quote_style(&args.quote_style))
+                .finish(&mut dataframe)?;
+        }
 
     // run provided GAQL query
     } else if args.gaql_query.is_some() {
+        // one client serves both account listing and querying, sharing the access token
+        let google_ads_client = api_context.client();
+
         // figure out which customerids to query for
-        let customer_ids: Option<Vec<String>> = 
+        let resolution_start = Instant::now();
+        match resolve_target_accounts(&args, &config, &google_ads_client).await {
+            Ok((source, customer_id_vector, login_customers)) => {
+                let resolution_duration = resolution_start.elapsed();
 
-            // if provided customerid and querying all child accounts, 
-            // then query all linked accounts under provided customerid
-            if args.customer_id.is_some() & args.all_linked_child_accounts {
-                let customer_id = args.customer_id.expect("Valid customer_id required.");
-                log::debug!("Querying child accounts under MCC: {}", &customer_id);
+                let fingerprint = {
+                    let query = args.gaql_query.clone().expect("Expected GAQL query");
+                    let queries = if chunk_queries.is_empty() { vec![query] } else { chunk_queries.clone() };
+                    util::query_fingerprint(&queries, &customer_id_vector, &query_map)
+                };
+                log::info!("Query fingerprint: {fingerprint}");
+
+                if args.print_fingerprint {
+                    println!("{fingerprint}");
+                    return Ok(());
+                }
+
+                if args.explain_plan {
+                    let query = args.gaql_query.as_deref().expect("Expected GAQL query");
+                    print_execution_plan(&args, &config, &source, &customer_id_vector, query);
+                    return Ok(());
+                }
+
+                if args.schema_preview {
+                    let query = args.gaql_query.as_deref().expect("Expected GAQL query");
+                    let Some(customer_id) = customer_id_vector.first() else {
+                        util::fail("No accounts to preview schema with");
+                    };
+                    let api_context = match login_customers.get(customer_id) {
+                        Some(mcc_id) => api_context.with_login_customer(mcc_id)?,
+                        None => api_context,
+                    };
+                    print_schema_preview(api_context, customer_id, query, &steps, &casts).await?;
+                    return Ok(());
+                }
+
+                if args.probe_fields {
+                    let query = args.gaql_query.as_deref().expect("Expected GAQL query");
+                    let Some(customer_id) = customer_id_vector.first() else {
+                        util::fail("No accounts to probe fields with");
+                    };
+                    let api_context = match login_customers.get(customer_id) {
+                        Some(mcc_id) => api_context.with_login_customer(mcc_id)?,
+                        None => api_context,
+                    };
+                    let mut dataframe = probe_fields(api_context, customer_id, query).await?;
+                    // print as TSV, since table display truncates long error messages
+                    CsvWriter::new(std::io::stdout())
+                        .with_separator(b'\t')
+                        .with_quote_style(quote_style(&args.quote_style))
+                        .finish(&mut dataframe)?;
+                    return Ok(());
+                }
 
-                // generate new list of child accounts
-                match googleads::get_child_account_ids(api_context.clone(), customer_id).await
-                    {
-                        Ok(customer_ids) => Some(customer_ids),
-                        Err(_e) => None,
+                if args.validate_query {
+                    let query = args.gaql_query.as_deref().expect("Expected GAQL query");
+                    let Some(customer_id) = customer_id_vector.first() else {
+                        util::fail("No accounts to validate query with");
+                    };
+                    let api_context = match login_customers.get(customer_id) {
+                        Some(mcc_id) => api_context.with_login_customer(mcc_id)?,
+                        None => api_context,
+                    };
+                    match googleads::validate_query(api_context, customer_id, query).await {
+                        Ok(()) => println!("Query is valid (validated against account {customer_id})."),
+                        Err(e) => util::fail(format!("Query is invalid: {e}")),
                     }
-            } 
-            // if provided customerid and not querying all child accounts, 
-            // then just query one account
-            else if args.customer_id.is_some() & !args.all_linked_child_accounts {
-                let customer_id = args.customer_id.expect("Valid customer_id required.");
-                log::debug!("Querying account: {customer_id}");
+                    return Ok(());
+                }
 
-                Some(vec![customer_id])
-            }
-            // if using default profile MCC and querying all child accounts,
-            // then query all linked accounts under profile mcc
-            else if args.customer_id.is_none() & args.all_linked_child_accounts {
-                let customer_id = config.mcc_customerid;
-                log::debug!("Querying all linked child accounts under profile MCC: {}", &customer_id);
+                // accounts under other manager accounts get a client sending their login MCC, one per MCC
+                let mut mcc_clients: HashMap<&str, GoogleAdsClient> = HashMap::new();
+                let mut account_clients: HashMap<String, GoogleAdsClient> = HashMap::new();
+                for (customer_id, mcc_id) in &login_customers {
+                    if !mcc_clients.contains_key(mcc_id.as_str()) {
+                        mcc_clients.insert(mcc_id, api_context.with_login_customer(mcc_id)?.client());
+                    }
+                    account_clients.insert(customer_id.clone(), mcc_clients[mcc_id.as_str()].clone());
+                }
 
-                // generate new list of child accounts
-                match googleads::get_child_account_ids(api_context.clone(), customer_id).await
-                    {
-                        Ok(customer_ids) => Some(customer_ids),
-                        Err(_e) => None,
+                if args.list_accessible {
+                    let query = args.gaql_query.as_deref().expect("Expected GAQL query");
+                    let mut dataframe =
+                        list_accessible_accounts(&google_ads_client, &account_clients, &customer_id_vector, query, &query_map)
+                            .await?;
+                    match &args.output {
+                        Some(outfile) => write_csv(
+                            &mut dataframe,
+                            outfile,
+                            quote_style(&args.quote_style),
+                            output_encoding.as_ref(),
+                            None,
+                        )?,
+                        None => println!("{dataframe}"),
                     }
-            }
-            // if using default profile MCC and NOT querying all child accounts, 
-            // then look for customerids file and use it
-            else if args.customer_id.is_none() & !args.all_linked_child_accounts {
-                if config.customerids_filename.is_some() {
-                    let customerids_path =
-                    crate::config::config_file_path(&config.customerids_filename.unwrap()).unwrap();
-                    log::debug!("Querying accounts listed in file: {}", customerids_path.display());
-
-                    match util::get_child_account_ids_from_file(customerids_path.as_path()).await 
-                        {
-                            Ok(customer_ids) => Some(customer_ids),
-                            Err(_e) => None,
-                        }
+                    return Ok(());
+                }
+
+                let query: String = args.gaql_query.expect("Expected GAQL query");
+
+                // unique per run unless supplied, for correlating logs and audit records across tools
+                let run_id = args.run_id.clone().unwrap_or_else(|| {
+                    let seed = format!("{query}\n{}\n{}", customer_id_vector.join(","), Utc::now().to_rfc3339());
+                    format!("{:016x}", util::stable_hash(&seed))
+                });
+
+                // cached results are merged after per-account transforms, so those options are part of the key
+                let results_cache = if args.cache_results {
+                    let key = util::stable_hash(&format!(
+                        "{fingerprint}\n{:?}",
+                        (
+                            &args.groupby,
+                            &args.agg,
+                            args.count_accounts_per_group,
+                            args.add_customer_id || args.compare_accounts.is_some(),
+                            &args.customer_id_column,
+                            &args.account_labels,
+                            args.reconstruct_resource_names,
+                            args.per_account_max_rows,
+                            &args.summary_mode,
+                        )
+                    ));
+                    let path = config::cache_file_path(&format!("results-{key:016x}.parquet"))
+                        .context("Unable to locate cache dir for results cache")?;
+                    Some(ResultsCache {
+                        path,
+                        ttl: Duration::from_secs(args.results_ttl),
+                        refresh: args.no_cache,
+                    })
                 } else {
-                    log::warn!("Expecting customerids file but none found in config");
                     None
+                };
+
+                // columns are typed and ordered by field metadata, falling back to field names without it
+                let date_filtered = !date_conditions.is_empty();
+                let fields = metadata_fields(
+                    &query,
+                    date_filtered.then_some(args.date_field.as_str()),
+                    args.smart_column_order,
+                );
+                let field_cache = if fields.is_empty() {
+                    googleads::read_field_cache()
+                } else {
+                    match googleads::load_field_cache(api_context.clone(), &fields).await {
+                        Ok(field_cache) => {
+                            if date_filtered {
+                                if let Err(e) = googleads::check_date_field(&args.date_field, &field_cache) {
+                                    util::fail(format!("Invalid --date-field: {e}"));
+                                }
+                            }
+                            field_cache
+                        }
+                        Err(e) => {
+                            log::warn!("Unable to look up field metadata, inferring column types from names: {e}");
+                            googleads::read_field_cache()
+                        }
+                    }
+                };
+
+                let options = QueryOptions {
+                    run_id,
+                    fingerprint,
+                    results_cache,
+                    chunk_queries,
+                    query_map,
+                    account_clients,
+                    groupby: args.groupby,
+                    count_accounts_per_group: args.count_accounts_per_group,
+                    aggs,
+                    sortby: args.sortby,
+                    reconstruct_resource_names: args.reconstruct_resource_names,
+                    // side-by-side comparison pivots on the customer_id column
+                    customer_id_column: (args.add_customer_id || args.compare_accounts.is_some())
+                        .then_some(args.customer_id_column),
+                    fetched_at_column: args.add_fetched_at.then_some(args.fetched_at_column),
+                    account_labels,
+                    probe: args.probe,
+                    summary_row_setting: googleads::summary_row_setting(&args.summary_mode),
+                    trace_dir: args.trace.map(PathBuf::from),
+                    steps,
+                    casts,
+                    humanize_metrics: args
+                        .humanize_metrics
+                        .then(|| config.percent_metrics.clone().unwrap_or_default()),
+                    compare_metric: args.compare_accounts,
+                    histogram_column: args.histogram,
+                    histogram_buckets: args.buckets,
+                    audit_log: args.audit_log.map(|path| AuditLog {
+                        path,
+                        mcc_id: config.mcc_customerid.clone(),
+                    }),
+                    quote_style: quote_style(&args.quote_style),
+                    encoding: output_encoding,
+                    split_by: args.split_by,
+                    outfile: args.output,
+                    max_table_rows: config.max_table_rows.unwrap_or(DEFAULT_MAX_TABLE_ROWS),
+                    assume_yes: args.yes,
+                    fail_fast: args.fail_fast,
+                    per_account_max_rows: args.per_account_max_rows,
+                    timeout_overall: args.timeout_overall.map(Duration::from_secs),
+                    size_order: args.order_accounts_by_size.as_deref().map(|order| match order {
+                        "largest-first" => SizeOrder::LargestFirst,
+                        "smallest-first" => SizeOrder::SmallestFirst,
+                        _ => SizeOrder::Unordered,
+                    }),
+                    retry_on_empty: args
+                        .retry_on_empty
+                        .then(|| (args.retry_empty_attempts, Duration::from_secs(args.retry_empty_delay))),
+                    prune_zero_rows: args.prune_zero_rows,
+                    smart_column_order: args.smart_column_order,
+                    field_cache,
+                    rename_map,
+                    strip_prefixes: args.strip_prefix,
+                    embed_schema_metadata: args.embed_schema_metadata,
+                    humanize_display: args
+                        .humanize
+                        .then(|| config.percent_metrics.clone().unwrap_or_default()),
+                    merge_with,
+                    #[cfg(feature = "sheets")]
+                    sheet: args.sheet.map(|spreadsheet_id| sheets::SheetTarget {
+                        spreadsheet_id,
+                        range: args.range,
+                        append: args.sheet_append,
+                        api_context: api_context.clone(),
+                    }),
+                };
+
+                let benchmark = args.benchmark;
+
+                // account listing may have taken a while, so make sure the token lasts into the query phase
+                if api_context.renew_token().await? {
+                    log::debug!("Renewed access token before query phase");
                 }
-            } else {
-                log::warn!("Not supposed to get here.");
-                None
-            };
 
+                // run queries asynchroughly across all customer_ids
+                let (summary, mut timings) =
+                    gaql_query_async(google_ads_client, customer_id_vector, query, options).await?;
 
-        // apply query to all customer_ids
-        if let Some(customer_id_vector) = customer_ids {
-            let query: String = args.gaql_query.expect("Expected GAQL query");
+                // only advance last run date when every account succeeded
+                if let Some(key) = &last_run_key {
+                    if summary.is_complete() {
+                        util::write_last_run_date(profile, key, Local::now().date_naive())?;
+                    } else {
+                        log::warn!("Not all accounts succeeded, keeping previous last run date");
+                    }
+                }
 
-            // run queries asynchroughly across all customer_ids
-            gaql_query_async(
-                api_context,
-                customer_id_vector,
-                query,
-                args.groupby,
-                args.sortby,
-                args.output,
-            )
-            .await?;
-        } else {
-            log::error!("Abort GAQL query. Can't find child accounts to run on.");
+                if benchmark {
+                    timings.auth = auth_duration;
+                    timings.account_resolution = resolution_duration;
+                    timings.print();
+                }
+
+                if summary.timed_out {
+                    process::exit(EXIT_CODE_TIMEOUT);
+                }
+            }
+            Err(e) => {
+                log::error!("Abort GAQL query. Can't find child accounts to run on: {e}");
+            }
         }
-        
     } else {
         println!("Nothing to do.");
     }
@@ -211,236 +709,2488 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn gaql_query_async(
-    api_context: GoogleAdsAPIAccess,
-    customer_id_vector: Vec<String>,
-    query: String,
-    groupby: Vec<String>,
-    sortby: Vec<String>,
-    outfile: Option<String>,
-) -> Result<()> {
-    log::info!(
-        "Running GAQL query for {} child accounts: {}",
-        &customer_id_vector.len(),
-        &query
-    );
+/// Where the CustomerIDs for a GAQL query come from
+enum AccountSource {
+    /// `--accounts` spec: inline list, file, or stdin
+    Spec(String),
+    /// file with one CustomerID per line
+    File(PathBuf),
+    /// a single CustomerID
+    Single(String),
+    /// all linked child accounts under a manager account
+    LinkedChildAccounts(String),
+    /// file of `mcc_id,customer_id` pairs, each queried with its own login MCC
+    Pairs(PathBuf),
+    /// linked child accounts under a manager account whose listing matches a filter expression
+    FilteredChildAccounts(String, String),
+}
+
+impl fmt::Display for AccountSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountSource::Spec(spec) => write!(f, "--accounts spec '{spec}'"),
+            AccountSource::File(path) => write!(f, "customerids file {}", path.display()),
+            AccountSource::Single(customer_id) => write!(f, "single account {customer_id}"),
+            AccountSource::LinkedChildAccounts(mcc_id) => {
+                write!(f, "all linked child accounts under MCC {mcc_id}")
+            }
+            AccountSource::Pairs(path) => write!(f, "account pairs file {}", path.display()),
+            AccountSource::FilteredChildAccounts(mcc_id, filter) => {
+                write!(f, "linked child accounts under MCC {mcc_id} matching '{filter}'")
+            }
+        }
+    }
+}
 
-    let google_ads_client: GoogleAdsServiceClient<InterceptedService<Channel, GoogleAdsAPIAccess>> =
-        GoogleAdsServiceClient::with_interceptor(api_context.channel.clone(), api_context);
+/// Determine where to get CustomerIDs from.
+///
+/// `--account-pairs` or `--accounts` take precedence, then `--account-filter` on child accounts of `--customer-id` or the profile MCC,
+/// then `--customer-id` (optionally with all linked child accounts),
+/// then all linked child accounts of the profile MCC, and finally the profile customerids file.
+fn get_account_source(args: &args::Cli, config: &config::MyConfig) -> Result<AccountSource> {
+    if let Some(path) = &args.account_pairs {
+        if args.accounts.is_some()
+            || args.customer_id.is_some()
+            || args.all_linked_child_accounts
+            || args.account_filter.is_some()
+        {
+            bail!("--account-pairs cannot be combined with --accounts, --customer-id, --all-linked-child-accounts or --account-filter");
+        }
+        return Ok(AccountSource::Pairs(PathBuf::from(path)));
+    }
 
-    let mut gaql_handles = FuturesUnordered::new();
+    if let Some(spec) = &args.accounts {
+        if args.customer_id.is_some() || args.all_linked_child_accounts || args.account_filter.is_some() {
+            bail!("--accounts cannot be combined with --customer-id, --all-linked-child-accounts or --account-filter");
+        }
+        return Ok(AccountSource::Spec(spec.clone()));
+    }
 
-    for customer_id in customer_id_vector.iter() {
-        // log::debug!("Querying {customer_id}");
+    if let Some(filter) = &args.account_filter {
+        let mcc_id = args.customer_id.as_ref().unwrap_or(&config.mcc_customerid);
+        if !util::is_valid_customer_id(mcc_id) {
+            bail!("--account-filter requires a single manager CustomerID, not '{mcc_id}'");
+        }
+        return Ok(AccountSource::FilteredChildAccounts(mcc_id.clone(), filter.clone()));
+    }
 
-        let gaql_future = googleads::gaql_query_with_client(
-            google_ads_client.clone(),
-            customer_id.clone(),
-            query.clone(),
-        );
+    if let Some(customer_id) = &args.customer_id {
+        // accept a customerids file passed in place of a single CustomerID
+        if !util::is_valid_customer_id(customer_id) {
+            if !Path::new(customer_id).is_file() {
+                bail!("Invalid CustomerID '{customer_id}': expected 10 digits without dashes, or a customerids file");
+            }
+            if args.all_linked_child_accounts {
+                bail!("--all-linked-child-accounts requires a single CustomerID, not a file: {customer_id}");
+            }
+            return Ok(AccountSource::File(PathBuf::from(customer_id)));
+        }
 
-        // execute gaql query in background thread
-        gaql_handles.push(tokio::spawn(gaql_future));
+        if args.all_linked_child_accounts {
+            Ok(AccountSource::LinkedChildAccounts(customer_id.clone()))
+        } else {
+            Ok(AccountSource::Single(customer_id.clone()))
+        }
+    } else if args.all_linked_child_accounts {
+        Ok(AccountSource::LinkedChildAccounts(config.mcc_customerid.clone()))
+    } else if let Some(customerids_filename) = &config.customerids_filename {
+        let customerids_path = crate::config::config_file_path(customerids_filename)
+            .context("Unable to locate config dir for customerids file")?;
+        Ok(AccountSource::File(customerids_path))
+    } else {
+        bail!("Expecting customerids file but none found in config");
     }
+}
 
-    let mut dataframes: Vec<DataFrame> = Vec::new();
-    let mut groupby_handles = FuturesUnordered::new();
-    let mut metrics_cols: Option<Vec<String>> = None;
+/// Resolve the CustomerIDs to run the GAQL query against,
+/// along with login MCCs for accounts that don't use the profile MCC
+async fn resolve_target_accounts(
+    args: &args::Cli,
+    config: &config::MyConfig,
+    google_ads_client: &GoogleAdsClient,
+) -> Result<(AccountSource, Vec<String>, HashMap<String, String>)> {
+    let source = get_account_source(args, config)?;
+    log::debug!("Querying accounts from {source}");
 
-    let start = Instant::now();
+    let mut login_customers: HashMap<String, String> = HashMap::new();
 
-    let mut total_rows: usize = 0;
-    let mut total_api_consumption: i64 = 0;
+    let mut customer_ids = match &source {
+        AccountSource::Pairs(path) => util::get_account_pairs_from_file(path)?
+            .into_iter()
+            .map(|(mcc_id, customer_id)| {
+                // the first pair listing an account decides its login MCC, matching dedup below
+                login_customers.entry(customer_id.clone()).or_insert(mcc_id);
+                customer_id
+            })
+            .collect(),
+        AccountSource::Spec(spec) => util::get_account_ids_from_spec(spec).await?,
+        AccountSource::File(path) => util::get_child_account_ids_from_file(path).await?,
+        AccountSource::Single(customer_id) => vec![customer_id.clone()],
+        AccountSource::LinkedChildAccounts(mcc_id) => {
+            googleads::get_child_account_ids(google_ads_client.clone(), mcc_id.clone()).await?
+        }
+        AccountSource::FilteredChildAccounts(mcc_id, filter) => {
+            filter_child_accounts(google_ads_client, mcc_id, filter).await?
+        }
+    };
 
-    // collect asynchronous query results
-    while let Some(result) = gaql_handles.next().await {
-        match result {
-            Ok(result) => {
-                match result {
-                    Ok((df, api_consumption)) => {
-                        if !df.is_empty() {
-                            total_rows += df.height();
-                            total_api_consumption += api_consumption;
+    // an account listed twice would be queried twice and double-counted in totals
+    let duplicates = util::dedup_account_ids(&mut customer_ids);
+    if !duplicates.is_empty() {
+        log::warn!(
+            "Removed {} duplicate accounts: {}",
+            duplicates.len().separate_with_commas(),
+            duplicates.join(", ")
+        );
+    }
 
-                            // get list of metrics columns
-                            if metrics_cols.is_none() {
-                                let cols:Vec<String> = df
-                                    .get_column_names()
-                                    .into_iter()
-                                    .filter(|c|c.contains("metrics"))
-                                    .map(|c| c.to_string()) 
-                                    .collect();
+    if args.shuffle_accounts {
+        // log seed so a random sample can be reproduced with `--shuffle-seed`
+        let seed = args.shuffle_seed.unwrap_or_else(rand::random);
+        log::info!("Shuffling accounts with seed {seed}");
+        customer_ids.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
 
-                                log::debug!("Metric cols: {:?}", cols);
+    if let Some(limit) = args.limit_accounts {
+        let total = customer_ids.len();
+        customer_ids.truncate(limit);
+        log::info!(
+            "Selected {} of {} accounts",
+            customer_ids.len().separate_with_commas(),
+            total.separate_with_commas()
+        );
+    }
 
-                                metrics_cols = Some(cols.clone());
-                            }
+    Ok((source, customer_ids, login_customers))
+}
 
-                            // check if groupby columns are in metrics columns
-                            for col in &groupby {
-                                if metrics_cols.as_ref().unwrap().contains(&col) {
-                                    let msg = format!("Groupby column cannot be a metric column: '{}'", col);
-                                    log::error!("{msg}");
-                                    return Err(anyhow::anyhow!(msg));
-                                }
-                            }
+/// List child accounts under a manager account, keeping those whose listing matches a filter expression.
+/// Listing columns are referenced without their `customer_client.` prefix.
+async fn filter_child_accounts(google_ads_client: &GoogleAdsClient, mcc_id: &str, filter: &str) -> Result<Vec<String>> {
+    let (filter_expr, columns) = expr::parse_expr(filter).context("Invalid --account-filter")?;
 
-                            // log::debug!("Future returned non-empty GAQL results");
-                            if !&groupby.is_empty() {
-                                // execute groupby in dedicated non-yielding thread
-                                let my_groupby = groupby.clone();
-                                let my_sortby = sortby.clone();
-                                let my_metrics_cols = metrics_cols.clone().unwrap();
-                                groupby_handles.push(tokio::task::spawn_blocking(|| {
-                                    apply_groupby(df, my_groupby, my_metrics_cols, my_sortby)
-                                }));
-                            } else {
-                                dataframes.push(df);
-                            }
-                        } else {
-                            // log::debug!("A future returned empty query results");
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("GAQL Error: {e}");
-                    }
-                }
-            }
-            Err(e) => {
-                log::error!("Thread JOIN Error: {e}");
-            }
-        }
+    let (mut df, _api_consumption, _truncated) = googleads::gaql_query_with_client(
+        google_ads_client.clone(),
+        mcc_id.to_owned(),
+        googleads::SUB_ACCOUNTS_QUERY.to_owned(),
+        SummaryRowSetting::NoSummaryRow,
+        None,
+        None,
+    )
+    .await?;
+    if df.width() == 0 {
+        log::warn!("No child accounts listed under MCC {mcc_id}");
+        return Ok(Vec::new());
     }
+    strip_column_prefix(&mut df, "customer_client")?;
 
-    let duration = start.elapsed();
+    let available: HashSet<String> = df
+        .get_column_names()
+        .into_iter()
+        .map(str::to_owned)
+        .collect();
+    expr::check_columns(&columns, &available, filter)?;
+
+    let total = df.height();
+    let df = df.lazy().filter(filter_expr).collect()?;
     log::info!(
-        "GAQL returned {} rows in {} msec across {} accounts using {} API units",
-        total_rows.separate_with_commas(),
-        duration.as_millis().separate_with_commas(),
-        customer_id_vector.len().separate_with_commas(),
-        total_api_consumption.separate_with_commas()
+        "--account-filter matched {} of {} child accounts",
+        df.height().separate_with_commas(),
+        total.separate_with_commas()
     );
 
-    // collect 1st pass groupby results
-    let groupby_start = Instant::now();
-    while let Some(result) = groupby_handles.next().await {
-        match result {
-            Ok(future) => {
-                match future.await {
-                    Ok(df) => {
-                        if !df.is_empty() {
-                            // log::debug!("Future returned GROUPBY results");
-                            dataframes.push(df);
-                        }
-                    }
-                    Err(e) => {
-                        log::error!("GROUPBY Error: {e}");
-                    }
-                }
+    let ids = df.column("id")?.cast(&DataType::String)?;
+    Ok(ids.str()?.into_no_null_iter().map(str::to_owned).collect())
+}
+
+/// Validate each SELECT field of the query in a query of its own, reporting which fields pass and the errors of those that fail
+async fn probe_fields(api_context: GoogleAdsAPIAccess, customer_id: &str, query: &str) -> Result<DataFrame> {
+    let fields = util::get_select_fields(query);
+    if fields.is_empty() {
+        bail!("No SELECT fields found in query");
+    }
+    log::info!("Probing {} fields with account {customer_id}", fields.len());
+
+    let mut passed: Vec<bool> = Vec::with_capacity(fields.len());
+    let mut errors: Vec<Option<String>> = Vec::with_capacity(fields.len());
+    for field in &fields {
+        let field_query = util::single_field_query(query, field).context("Unable to find FROM clause in query")?;
+        log::debug!("Probing field {field}: {field_query}");
+        match googleads::validate_query(api_context.clone(), customer_id, &field_query).await {
+            Ok(()) => {
+                passed.push(true);
+                errors.push(None);
             }
             Err(e) => {
-                log::error!("Thread JOIN Error: {e}");
+                passed.push(false);
+                errors.push(Some(e.to_string()));
             }
         }
     }
-    let groupby_duration = groupby_start.elapsed();
-    log::debug!(
-        "1st pass groupby used additional foreground time of {} msec",
-        groupby_duration.as_millis().separate_with_commas()
-    );
 
-    if !dataframes.is_empty() {
-        let start = Instant::now();
+    Ok(DataFrame::new(vec![
+        Series::new("field", fields),
+        Series::new("valid", passed),
+        Series::new("error", errors),
+    ])?)
+}
+
+/// Print result column names and types, by running the query with LIMIT 1 against one account
+async fn print_schema_preview(
+    api_context: GoogleAdsAPIAccess,
+    customer_id: &str,
+    query: &str,
+    steps: &[expr::ParsedStep],
+    casts: &[(String, DataType)],
+) -> Result<()> {
+    let preview_query = util::add_limit(query, 1);
+    log::info!("Previewing schema with account {customer_id}: {preview_query}");
+
+    let (mut df, _api_consumption) =
+        googleads::gaql_query(api_context, customer_id.to_owned(), preview_query).await?;
+    if df.width() == 0 {
+        bail!("Account {customer_id} returned no columns. Try --customer-id with another account.");
+    }
+
+    if !steps.is_empty() {
+        df = expr::apply_steps(df, steps)?;
+    }
+    apply_casts(&mut df, casts)?;
+
+    for (name, dtype) in df.schema().iter() {
+        println!("{name}\t{dtype}");
+    }
+
+    Ok(())
+}
+
+/// Print what a GAQL run would do, without executing the query
+fn print_execution_plan(
+    args: &args::Cli,
+    config: &config::MyConfig,
+    source: &AccountSource,
+    customer_ids: &[String],
+    query: &str,
+) {
+    println!("Execution plan:");
+    println!("  Profile:     {}", args.profile.as_deref().unwrap_or("test"));
+    match source {
+        AccountSource::Pairs(_) => println!("  Login MCC:   per account, from pairs file"),
+        _ => println!("  Login MCC:   {}", config.mcc_customerid),
+    }
+    println!("  Accounts:    {} ({} accounts)", source, customer_ids.len());
+    println!("  Query:       {}", query.trim());
+    println!("  Group by:    {:?}", args.groupby);
+    println!("  Aggregate:   {:?}", args.agg);
+    println!("  Sort by:     {:?}", args.sortby);
+    println!(
+        "  Rate limits: {} requests/sec, {} concurrent requests",
+        googleads::RATE_LIMIT_PER_SEC,
+        googleads::CONCURRENCY_LIMIT
+    );
+    println!(
+        "  Output:      {}",
+        args.output.as_deref().unwrap_or("stdout")
+    );
+}
+
+/// Run each chunk query against an account in turn, merging results.
+/// Rows returned by more than one chunk, e.g. with `CONTAINS ANY` lists, are kept once.
+/// `max_rows` caps rows across all chunks, and chunks left unrun once it is used up count as truncation.
+async fn gaql_query_chunks(
+    client: GoogleAdsClient,
+    customer_id: String,
+    queries: Vec<String>,
+    summary_row_setting: SummaryRowSetting,
+    trace_dir: Option<PathBuf>,
+    max_rows: Option<usize>,
+) -> Result<(DataFrame, i64, bool)> {
+    let mut merged: Option<DataFrame> = None;
+    let mut total_api_consumption = 0;
+    let mut any_truncated = false;
+
+    for query in &queries {
+        let remaining_rows = max_rows.map(|max| max.saturating_sub(merged.as_ref().map_or(0, DataFrame::height)));
+        if remaining_rows == Some(0) {
+            log::warn!("Account {customer_id}: row cap reached, skipping remaining chunks");
+            any_truncated = true;
+            break;
+        }
+
+        let (df, api_consumption, truncated) = googleads::gaql_query_with_client(
+            client.clone(),
+            customer_id.clone(),
+            query.clone(),
+            summary_row_setting,
+            trace_dir.clone(),
+            remaining_rows,
+        )
+        .await?;
+        total_api_consumption += api_consumption;
+        any_truncated |= truncated;
+
+        // chunks without a field mask come back without columns
+        merged = match merged {
+            Some(merged) if df.width() == 0 => Some(merged),
+            Some(merged) if merged.width() > 0 => Some(merged.vstack(&df)?),
+            _ => Some(df),
+        };
+    }
+
+    let mut df = merged.unwrap_or_default();
+    if queries.len() > 1 && df.width() > 0 {
+        df = df.unique_stable(None, UniqueKeepStrategy::First, None)?;
+    }
+
+    Ok((df, total_api_consumption, any_truncated))
+}
+
+/// Run chunk queries against an account, retrying up to `retry_on_empty` extra times while it returns no rows.
+/// Returns the results along with the number of retries made.
+async fn gaql_query_retrying_empty(
+    client: GoogleAdsClient,
+    customer_id: String,
+    queries: Vec<String>,
+    summary_row_setting: SummaryRowSetting,
+    trace_dir: Option<PathBuf>,
+    max_rows: Option<usize>,
+    retry_on_empty: Option<(usize, Duration)>,
+) -> Result<(DataFrame, i64, bool, usize)> {
+    let (max_retries, delay) = retry_on_empty.unwrap_or((0, Duration::ZERO));
+    let mut total_api_consumption = 0;
+    let mut retries = 0;
+
+    loop {
+        let (df, api_consumption, truncated) = gaql_query_chunks(
+            client.clone(),
+            customer_id.clone(),
+            queries.clone(),
+            summary_row_setting,
+            trace_dir.clone(),
+            max_rows,
+        )
+        .await?;
+        total_api_consumption += api_consumption;
+
+        if !df.is_empty() || retries >= max_retries {
+            return Ok((df, total_api_consumption, truncated, retries));
+        }
+
+        retries += 1;
+        log::debug!(
+            customer_id = customer_id.as_str();
+            "Account {customer_id} returned no rows, retrying in {} sec ({retries}/{max_retries})",
+            delay.as_secs()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// `--order-accounts-by-size` dispatch order
+#[derive(Clone, Copy, PartialEq)]
+enum SizeOrder {
+    LargestFirst,
+    SmallestFirst,
+    /// only record sizes
+    Unordered,
+}
+
+/// Order accounts by row counts recorded for previous runs of the query. Accounts without history go last, in original order.
+fn order_accounts_by_size(mut customer_ids: Vec<String>, query: &str, order: SizeOrder) -> Vec<String> {
+    let sizes = match util::read_account_sizes(query) {
+        Ok(sizes) => sizes,
+        Err(e) => {
+            log::warn!("Not ordering accounts by size: {e}");
+            return customer_ids;
+        }
+    };
+    if sizes.is_empty() {
+        log::info!("No account sizes recorded for this query yet, keeping account order");
+        return customer_ids;
+    }
+
+    customer_ids.sort_by_key(|customer_id| match (sizes.get(customer_id), order) {
+        (None, _) => (1, 0),
+        (Some(&rows), SizeOrder::LargestFirst) => (0, usize::MAX - rows),
+        (Some(&rows), _) => (0, rows),
+    });
+    log::info!(
+        "Ordered accounts by size from {} recorded row counts",
+        sizes.len().separate_with_commas()
+    );
+
+    customer_ids
+}
+
+/// Run query, or the account's own `--query-map` query, with LIMIT 1 against each account, returning accounts that succeeded in original order.
+/// Failed accounts are recorded in the summary under a `Probe` error kind.
+async fn probe_accounts(
+    google_ads_client: &GoogleAdsClient,
+    account_clients: &HashMap<String, GoogleAdsClient>,
+    customer_ids: &[String],
+    query: &str,
+    query_map: &HashMap<String, String>,
+    summary: &mut RunSummary,
+) -> Vec<String> {
+    let (probes, join_errors) = run_probes(google_ads_client, account_clients, customer_ids, query, query_map).await;
+    for _ in 0..join_errors {
+        summary.record_error("TaskJoinError".to_owned(), "unknown".to_owned());
+    }
+
+    let mut failed: HashSet<String> = HashSet::new();
+    for (customer_id, error) in probes {
+        if let Some(e) = error {
+            summary.record_error(format!("Probe {}", googleads::error_kind(&e)), customer_id.clone());
+            failed.insert(customer_id);
+        }
+    }
+
+    if !failed.is_empty() {
+        let msg = format!(
+            "Skipping {} of {} accounts that failed the probe",
+            failed.len().separate_with_commas(),
+            customer_ids.len().separate_with_commas()
+        );
+        log::warn!("{msg}");
+        eprintln!("{msg}");
+    }
+
+    customer_ids
+        .iter()
+        .filter(|customer_id| !failed.contains(*customer_id))
+        .cloned()
+        .collect()
+}
+
+/// Report whether each account accepts the query, by probing it with LIMIT 1.
+/// `accessible` is null for accounts whose probe did not complete.
+async fn list_accessible_accounts(
+    google_ads_client: &GoogleAdsClient,
+    account_clients: &HashMap<String, GoogleAdsClient>,
+    customer_ids: &[String],
+    query: &str,
+    query_map: &HashMap<String, String>,
+) -> Result<DataFrame> {
+    let (mut probes, _join_errors) = run_probes(google_ads_client, account_clients, customer_ids, query, query_map).await;
+
+    let mut accessible: Vec<Option<bool>> = Vec::with_capacity(customer_ids.len());
+    let mut errors: Vec<Option<String>> = Vec::with_capacity(customer_ids.len());
+    for customer_id in customer_ids {
+        match probes.remove(customer_id) {
+            Some(None) => {
+                accessible.push(Some(true));
+                errors.push(None);
+            }
+            Some(Some(e)) => {
+                accessible.push(Some(false));
+                errors.push(Some(format!("{}: {e}", googleads::error_kind(&e))));
+            }
+            None => {
+                accessible.push(None);
+                errors.push(Some("probe did not complete".to_owned()));
+            }
+        }
+    }
+
+    Ok(DataFrame::new(vec![
+        Series::new("customer_id", customer_ids),
+        Series::new("accessible", accessible),
+        Series::new("error", errors),
+    ])?)
+}
+
+/// Run query, or the account's own `--query-map` query, with LIMIT 1 against each account concurrently.
+/// Returns each completed probe's error, if any, by customer_id, and the number of probe tasks that failed to complete.
+async fn run_probes(
+    google_ads_client: &GoogleAdsClient,
+    account_clients: &HashMap<String, GoogleAdsClient>,
+    customer_ids: &[String],
+    query: &str,
+    query_map: &HashMap<String, String>,
+) -> (HashMap<String, Option<anyhow::Error>>, usize) {
+    let probe_query = util::add_limit(query, 1);
+    log::info!(
+        "Probing {} accounts with: {probe_query}",
+        customer_ids.len().separate_with_commas()
+    );
+
+    let mut probe_handles = FuturesUnordered::new();
+    for customer_id in customer_ids {
+        let probe_query = match query_map.get(customer_id) {
+            Some(mapped_query) => util::add_limit(mapped_query, 1),
+            None => probe_query.clone(),
+        };
+        let probe_future = googleads::gaql_query_with_client(
+            account_clients.get(customer_id).unwrap_or(google_ads_client).clone(),
+            customer_id.clone(),
+            probe_query,
+            SummaryRowSetting::NoSummaryRow,
+            None,
+            None,
+        );
+        let customer_id = customer_id.clone();
+        probe_handles.push(tokio::spawn(async move { (customer_id, probe_future.await) }));
+    }
+
+    let mut probes: HashMap<String, Option<anyhow::Error>> = HashMap::new();
+    let mut join_errors = 0;
+    while let Some(result) = probe_handles.next().await {
+        match result {
+            Ok((customer_id, Ok(_))) => {
+                probes.insert(customer_id, None);
+            }
+            Ok((customer_id, Err(e))) => {
+                log::warn!(customer_id = customer_id.as_str(); "Probe failed for account {customer_id}: {e}");
+                probes.insert(customer_id, Some(e));
+            }
+            Err(e) => {
+                log::error!("Thread JOIN Error: {e}");
+                join_errors += 1;
+            }
+        }
+    }
+
+    (probes, join_errors)
+}
+
+/// Options controlling how GAQL results are post-processed and written
+struct QueryOptions {
+    /// identifies this run in logs and the audit log
+    run_id: String,
+    /// identifies the effective query and target accounts, shared by runs of the same logical query
+    fingerprint: String,
+    /// cache of merged results to reuse instead of querying, if any
+    results_cache: Option<ResultsCache>,
+    /// query per chunk of a `--chunk-param` IN-list, run in turn for each account; empty if not chunked
+    chunk_queries: Vec<String>,
+    /// queries run in place of the default for some accounts, by customer_id
+    query_map: HashMap<String, String>,
+    /// clients for accounts queried with a login MCC other than the profile's, by customer_id
+    account_clients: HashMap<String, GoogleAdsClient>,
+    /// columns to group by
+    groupby: Vec<String>,
+    /// add a count of distinct accounts per group
+    count_accounts_per_group: bool,
+    /// columns to sort by
+    sortby: Vec<String>,
+    /// metric aggregations other than sum, by column
+    aggs: Vec<(String, Aggregation)>,
+    /// rebuild resource_name columns from id columns
+    reconstruct_resource_names: bool,
+    /// name of column to prepend with each row's source customer_id, if any
+    customer_id_column: Option<String>,
+    /// name of fetch timestamp column to append, if any
+    fetched_at_column: Option<String>,
+    /// friendly account names by customer_id, added as a `customer_name` column
+    account_labels: Option<HashMap<String, String>>,
+    /// probe accounts with LIMIT 1 first, skipping those that fail
+    probe: bool,
+    /// server-computed summary row to request
+    summary_row_setting: SummaryRowSetting,
+    /// directory to save raw API responses to, per account
+    trace_dir: Option<PathBuf>,
+    /// post-processing steps applied to the merged results
+    steps: Vec<expr::ParsedStep>,
+    /// column type overrides applied before output
+    casts: Vec<(String, DataType)>,
+    /// convert metrics to human-friendly units, with extra percent metrics from config
+    humanize_metrics: Option<Vec<String>>,
+    /// metric to pivot into one column per account
+    compare_metric: Option<String>,
+    /// numeric column to summarize as a histogram instead of outputting rows
+    histogram_column: Option<String>,
+    /// number of equal-width histogram buckets
+    histogram_buckets: usize,
+    /// compliance log to append a record of this run to, if any
+    audit_log: Option<AuditLog>,
+    /// CSV quoting for file output
+    quote_style: QuoteStyle,
+    /// encoding of CSV files, if not UTF-8
+    encoding: Option<OutputEncoding>,
+    /// column whose distinct values split output into separate files
+    split_by: Option<String>,
+    /// output filename, or stdout if none
+    outfile: Option<String>,
+    /// rows to print to a terminal without confirmation
+    max_table_rows: usize,
+    /// skip confirmation prompts
+    assume_yes: bool,
+    /// abort remaining accounts on the first account error
+    fail_fast: bool,
+    /// max rows read per account before truncating its results
+    per_account_max_rows: Option<usize>,
+    /// wall-clock budget for querying all accounts, after which partial results are written
+    timeout_overall: Option<Duration>,
+    /// dispatch order by row counts of previous runs, which are recorded when set
+    size_order: Option<SizeOrder>,
+    /// extra attempts, and delay between them, for accounts returning no rows
+    retry_on_empty: Option<(usize, Duration)>,
+    /// drop rows where every metric is zero or null
+    prune_zero_rows: bool,
+    /// order columns as resource attributes, then segments, then metrics
+    smart_column_order: bool,
+    /// metadata of queried fields, for typing and ordering columns
+    field_cache: googleads::FieldCache,
+    /// output column renames, as from and to names
+    rename_map: Vec<(String, String)>,
+    /// resources whose `<resource>.` prefix is removed from output column names
+    strip_prefixes: Vec<String>,
+    /// start CSV files with comment lines describing the schema
+    embed_schema_metadata: bool,
+    /// extra percent metrics, when formatting metrics for readability in table output
+    humanize_display: Option<Vec<String>>,
+    /// local dataset to left join onto results, with its key columns
+    merge_with: Option<(DataFrame, Vec<String>)>,
+    /// Google Sheet to write to, instead of outfile or stdout
+    #[cfg(feature = "sheets")]
+    sheet: Option<sheets::SheetTarget>,
+}
+
+async fn gaql_query_async(
+    google_ads_client: GoogleAdsClient,
+    customer_id_vector: Vec<String>,
+    query: String,
+    options: QueryOptions,
+) -> Result<(RunSummary, StageTimings)> {
+    let QueryOptions {
+        run_id,
+        fingerprint,
+        results_cache,
+        chunk_queries,
+        query_map,
+        account_clients,
+        groupby,
+        count_accounts_per_group,
+        sortby,
+        aggs,
+        reconstruct_resource_names,
+        customer_id_column,
+        fetched_at_column,
+        account_labels,
+        probe,
+        summary_row_setting,
+        trace_dir,
+        steps,
+        casts,
+        humanize_metrics,
+        compare_metric,
+        histogram_column,
+        histogram_buckets,
+        audit_log,
+        quote_style,
+        encoding,
+        split_by,
+        outfile,
+        max_table_rows,
+        assume_yes,
+        fail_fast,
+        per_account_max_rows,
+        timeout_overall,
+        size_order,
+        retry_on_empty,
+        prune_zero_rows,
+        smart_column_order,
+        field_cache,
+        rename_map,
+        strip_prefixes,
+        embed_schema_metadata,
+        humanize_display,
+        merge_with,
+        #[cfg(feature = "sheets")]
+        sheet,
+    } = options;
+
+    eprintln!("Run ID: {run_id}");
+    log::info!(
+        run_id = run_id.as_str();
+        "Run {run_id}: running GAQL query for {} child accounts: {}",
+        &customer_id_vector.len(),
+        &query
+    );
+
+    // budget starts before any probe, so probe time counts against it, but only account queries are cut off
+    let overall_deadline = timeout_overall.map(|timeout| tokio::time::Instant::now() + timeout);
+
+    if let Some(dir) = &trace_dir {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Unable to create trace dir {}", dir.display()))?;
+    }
+
+    let mut summary = RunSummary::default();
+
+    // fresh cached results stand in for querying any accounts
+    let cached = match &results_cache {
+        Some(cache) => cache.load()?,
+        None => None,
+    };
+    let from_cache = cached.is_some();
+
+    // front-load access failures with a cheap LIMIT 1 run, and skip failed accounts in the full run
+    let target_ids = if from_cache {
+        Vec::new()
+    } else if probe {
+        probe_accounts(&google_ads_client, &account_clients, &customer_id_vector, &query, &query_map, &mut summary).await
+    } else {
+        customer_id_vector.clone()
+    };
+
+    // bigger accounts started early are less likely to hold up the end of the run
+    let target_ids = match size_order {
+        Some(order) if order != SizeOrder::Unordered => order_accounts_by_size(target_ids, &query, order),
+        _ => target_ids,
+    };
+    let mut account_sizes: HashMap<String, usize> = HashMap::new();
+
+    let queries = if chunk_queries.is_empty() {
+        vec![query.clone()]
+    } else {
+        chunk_queries
+    };
+
+    let custom_query_ids: Vec<&str> = target_ids
+        .iter()
+        .filter(|customer_id| query_map.contains_key(*customer_id))
+        .map(String::as_str)
+        .collect();
+    if !custom_query_ids.is_empty() {
+        let msg = format!(
+            "Using --query-map queries for {} accounts: {}",
+            custom_query_ids.len().separate_with_commas(),
+            custom_query_ids.join(", ")
+        );
+        log::info!("{msg}");
+        eprintln!("{msg}");
+    }
+    if custom_query_ids.len() < query_map.len() {
+        log::warn!(
+            "{} --query-map accounts are not among the accounts queried",
+            (query_map.len() - custom_query_ids.len()).separate_with_commas()
+        );
+    }
+
+    let mut gaql_handles = FuturesUnordered::new();
+
+    for customer_id in target_ids.iter() {
+        // log::debug!("Querying {customer_id}");
+
+        let gaql_future = gaql_query_retrying_empty(
+            account_clients.get(customer_id).unwrap_or(&google_ads_client).clone(),
+            customer_id.clone(),
+            // mapped accounts run their own query, unchunked
+            match query_map.get(customer_id) {
+                Some(mapped_query) => vec![mapped_query.clone()],
+                None => queries.clone(),
+            },
+            summary_row_setting,
+            trace_dir.clone(),
+            per_account_max_rows,
+            retry_on_empty,
+        );
+
+        // execute gaql query in background thread, keeping track of which account it is for
+        let customer_id = customer_id.clone();
+        gaql_handles.push(tokio::spawn(async move { (customer_id, gaql_future.await) }));
+    }
+
+    // timestamp is taken once so it is constant across all rows of this run;
+    // cached results keep the time they were originally fetched
+    let fetched_at_time = match &cached {
+        Some((_, fetched_at)) => *fetched_at,
+        None => Utc::now(),
+    };
+    let fetched_at = fetched_at_time.to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let mut dataframes: Vec<DataFrame> = Vec::new();
+    let mut groupby_handles = FuturesUnordered::new();
+    let mut metrics_cols: Option<Vec<String>> = None;
+
+    let start = Instant::now();
+
+    let mut timings = StageTimings::default();
+
+    // on first Ctrl-C, stop waiting after a grace period and write partial results
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    let mut grace_deadline: Option<tokio::time::Instant> = None;
+
+    // collect asynchronous query results
+    loop {
+        let result = if let Some(deadline) = grace_deadline {
+            match tokio::time::timeout_at(deadline, gaql_handles.next()).await {
+                Ok(Some(result)) => result,
+                _ => break,
+            }
+        } else {
+            tokio::select! {
+                result = gaql_handles.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                _ = async {
+                    match overall_deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    summary.timed_out = true;
+                    break;
+                }
+                _ = &mut ctrl_c => {
+                    eprintln!(
+                        "Interrupted: waiting up to {} sec for in-flight queries, then writing partial results. Press Ctrl-C again to quit immediately.",
+                        INTERRUPT_GRACE_PERIOD.as_secs()
+                    );
+                    // second Ctrl-C force-quits
+                    tokio::spawn(async {
+                        if tokio::signal::ctrl_c().await.is_ok() {
+                            eprintln!("Interrupted again, exiting.");
+                            process::exit(130);
+                        }
+                    });
+                    grace_deadline = Some(tokio::time::Instant::now() + INTERRUPT_GRACE_PERIOD);
+                    continue;
+                }
+            }
+        };
+
+        match result {
+            Ok((customer_id, result)) => {
+                match result {
+                    Ok((mut df, api_consumption, truncated, empty_retries)) => {
+                        if truncated {
+                            summary.truncated.push(customer_id.clone());
+                        }
+                        if empty_retries > 0 {
+                            summary.empty_retried.push(customer_id.clone());
+                        }
+                        if size_order.is_some() {
+                            account_sizes.insert(customer_id.clone(), df.height());
+                        }
+
+                        if !df.is_empty() {
+                            if reconstruct_resource_names {
+                                googleads::add_resource_names(&mut df, &customer_id)?;
+                            }
+
+                            // record which account each row came from
+                            if let Some(column_name) = &customer_id_column {
+                                add_customer_id_column(&mut df, column_name, &customer_id)?;
+                            }
+
+                            // accounts missing from the labels file get a null name
+                            if let Some(labels) = &account_labels {
+                                let name = labels.get(&customer_id).map(String::as_str);
+                                let values = vec![name; df.height()];
+                                let idx = usize::from(customer_id_column.is_some());
+                                df.insert_column(idx, Series::new(ACCOUNT_LABEL_COLUMN, values))?;
+                            }
+
+                            summary.with_rows += 1;
+                            summary.total_rows += df.height();
+                            summary.api_units += api_consumption;
+
+                            // get list of metrics columns
+                            if metrics_cols.is_none() {
+                                let cols:Vec<String> = df
+                                    .get_column_names()
+                                    .into_iter()
+                                    .filter(|c|c.contains("metrics"))
+                                    .map(|c| c.to_string()) 
+                                    .collect();
+
+                                log::debug!("Metric cols: {:?}", cols);
+
+                                metrics_cols = Some(cols.clone());
+                            }
+
+                            // check if groupby columns are in metrics columns
+                            for col in &groupby {
+                                if metrics_cols.as_ref().unwrap().contains(&col) {
+                                    let msg = format!("Groupby column cannot be a metric column: '{}'", col);
+                                    log::error!("{msg}");
+                                    return Err(anyhow::anyhow!(msg));
+                                }
+                            }
+
+                            check_aggs(&aggs, &df, metrics_cols.as_ref().unwrap())?;
+
+                            // log::debug!("Future returned non-empty GAQL results");
+                            if !&groupby.is_empty() {
+                                // execute groupby in dedicated non-yielding thread
+                                let my_groupby = groupby.clone();
+                                let my_sortby = sortby.clone();
+                                let my_metrics_cols = metrics_cols.clone().unwrap();
+                                let my_aggs = aggs.clone();
+                                groupby_handles.push(tokio::task::spawn_blocking(move || {
+                                    apply_groupby(df, my_groupby, my_metrics_cols, my_aggs, my_sortby, count_accounts_per_group)
+                                }));
+                            } else {
+                                dataframes.push(df);
+                            }
+                        } else {
+                            // log::debug!("A future returned empty query results");
+                            summary.empty += 1;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!(customer_id = customer_id.as_str(), run_id = run_id.as_str(); "GAQL Error: {e}");
+
+                        if fail_fast {
+                            // stop queries still running for other accounts
+                            for handle in gaql_handles.iter() {
+                                handle.abort();
+                            }
+                            log::warn!(
+                                customer_id = customer_id.as_str(), run_id = run_id.as_str();
+                                "Aborted {} in-flight queries after error in account {customer_id}",
+                                gaql_handles.len().separate_with_commas()
+                            );
+                            return Err(e.context(format!("Aborted on first error (--fail-fast), account {customer_id}")));
+                        }
+
+                        summary.record_error(googleads::error_kind(&e), customer_id);
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Thread JOIN Error: {e}");
+                summary.record_error("TaskJoinError".to_owned(), "unknown".to_owned());
+            }
+        }
+    }
+
+    if grace_deadline.is_some() || summary.timed_out {
+        // stop queries still running after grace period or overall timeout
+        for handle in gaql_handles.iter() {
+            handle.abort();
+        }
+        summary.incomplete = gaql_handles.len();
+        let reason = if summary.timed_out {
+            format!("Overall timeout of {} sec exceeded", timeout_overall.unwrap_or_default().as_secs())
+        } else {
+            "Interrupted".to_owned()
+        };
+        let msg = format!(
+            "{reason}, partial results: {} of {} accounts completed, {} cut off",
+            (target_ids.len() - gaql_handles.len()).separate_with_commas(),
+            target_ids.len().separate_with_commas(),
+            gaql_handles.len().separate_with_commas()
+        );
+        log::warn!("{msg}");
+        eprintln!("{msg}");
+    }
+
+    if let Some((df, _)) = cached {
+        summary.total_rows = df.height();
+        metrics_cols = Some(
+            df.get_column_names()
+                .into_iter()
+                .filter(|c| c.contains("metrics"))
+                .map(str::to_owned)
+                .collect(),
+        );
+        dataframes.push(df);
+    } else {
+        summary.print();
+    }
+
+    let duration = start.elapsed();
+    timings.query = duration;
+    log::info!(
+        run_id = run_id.as_str();
+        "Run {run_id}: GAQL returned {} rows in {} msec across {} accounts using {} API units",
+        summary.total_rows.separate_with_commas(),
+        duration.as_millis().separate_with_commas(),
+        customer_id_vector.len().separate_with_commas(),
+        summary.api_units.separate_with_commas()
+    );
+
+    if size_order.is_some() {
+        if let Err(e) = util::write_account_sizes(&query, &account_sizes) {
+            log::warn!("Unable to record account sizes: {e}");
+        }
+    }
+
+    // written before post-processing so partial and failed runs are still recorded
+    if let Some(audit_log) = &audit_log {
+        audit_log.append(&run_id, &fingerprint, &query, &customer_id_vector, &summary)?;
+    }
+
+    // collect 1st pass groupby results
+    let groupby_start = Instant::now();
+    while let Some(result) = groupby_handles.next().await {
+        match result {
+            Ok(future) => {
+                match future.await {
+                    Ok(df) => {
+                        if !df.is_empty() {
+                            // log::debug!("Future returned GROUPBY results");
+                            dataframes.push(df);
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("GROUPBY Error: {e}");
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("Thread JOIN Error: {e}");
+            }
+        }
+    }
+    let groupby_duration = groupby_start.elapsed();
+    timings.groupby_first_pass = groupby_duration;
+    log::debug!(
+        "1st pass groupby used additional foreground time of {} msec",
+        groupby_duration.as_millis().separate_with_commas()
+    );
+
+    if !dataframes.is_empty() {
+        let start = Instant::now();
         let len = &dataframes.len();
 
         // merge dataframes
         let mut df_iter = dataframes.into_iter();
         let mut dataframe: DataFrame = df_iter.next().unwrap();
 
-        #[allow(clippy::while_let_on_iterator)]
-        while let Some(df) = df_iter.next() {
-            dataframe = dataframe.vstack(&df)?;
+        #[allow(clippy::while_let_on_iterator)]
+        while let Some(df) = df_iter.next() {
+            dataframe = dataframe.vstack(&df)?;
+        }
+
+        let duration = start.elapsed();
+        timings.merge = duration;
+        log::debug!(
+            "merged {:#} dataframes in {} msec",
+            len,
+            duration.as_millis().separate_with_commas()
+        );
+
+        // partial results would be reused as if complete, so only complete runs are cached
+        if let Some(cache) = results_cache.as_ref().filter(|_| !from_cache && summary.is_complete()) {
+            if let Err(e) = cache.store(&mut dataframe, fetched_at_time) {
+                log::warn!("Unable to cache results: {e}");
+            }
+        }
+
+        googleads::parse_temporal_columns(&mut dataframe, &field_cache)?;
+
+        // apply 2nd pass gropuby/sortby
+        if !groupby.is_empty() || !sortby.is_empty() {
+            let start = Instant::now();
+
+            log::info!("Applying global groupby with columns: {:?}", groupby);
+            log::info!("Applying global sortby with columns: {:?}", sortby);
+
+            dataframe = apply_groupby(dataframe, groupby.clone(), metrics_cols.clone().unwrap(), aggs.clone(), sortby.clone(), count_accounts_per_group).await?;
+
+            let duration = start.elapsed();
+            timings.groupby_second_pass = duration;
+            log::debug!(
+                "applied 2nd pass groupby in {} msec",
+                duration.as_millis().separate_with_commas()
+            );
+        }
+
+        if prune_zero_rows {
+            dataframe = drop_zero_metric_rows(dataframe)?;
+        }
+
+        if let Some((local, keys)) = &merge_with {
+            dataframe = join_local_dataset(dataframe, local, keys)?;
+        }
+
+        if let Some(column_name) = &fetched_at_column {
+            add_fetched_at_column(&mut dataframe, column_name, &fetched_at)?;
+        }
+
+        // before steps, so an explicit `select` order wins
+        if smart_column_order {
+            dataframe = dataframe.select(smart_column_order_of(&dataframe.get_column_names(), &field_cache))?;
+        }
+
+        if !steps.is_empty() {
+            dataframe = expr::apply_steps(dataframe, &steps)?;
+        }
+
+        apply_casts(&mut dataframe, &casts)?;
+
+        if let Some(percent_metrics) = &humanize_metrics {
+            googleads::humanize_metrics(&mut dataframe, percent_metrics)?;
+        }
+
+        if let Some(metric) = &compare_metric {
+            let customer_id_column = customer_id_column.as_deref().expect("customer_id column for comparison");
+            let metric = compare_metric_column(&dataframe, metric, humanize_metrics.as_deref());
+            let mut per_account = vec![ACCOUNT_LABEL_COLUMN];
+            per_account.extend(fetched_at_column.as_deref());
+            dataframe = compare_accounts(&dataframe, customer_id_column, &metric, &per_account)?;
+        }
+
+        if let Some(column) = &histogram_column {
+            dataframe = histogram(&dataframe, column, histogram_buckets)?;
+        }
+
+        if !rename_map.is_empty() {
+            rename_columns(&mut dataframe, &rename_map)?;
+        }
+
+        for prefix in &strip_prefixes {
+            if strip_column_prefix(&mut dataframe, prefix)? == 0 {
+                log::warn!("--strip-prefix {prefix} matches no columns");
+            }
+        }
+
+        log::debug!("final dataframe shape: {:?}", dataframe.shape());
+
+        #[cfg(feature = "sheets")]
+        if let Some(target) = &sheet {
+            let start = Instant::now();
+            target.write(&dataframe).await?;
+            timings.output = start.elapsed();
+            return Ok((summary, timings));
+        }
+
+        if outfile.is_some() {
+            let start = Instant::now();
+
+            let header = embed_schema_metadata.then(|| schema_metadata(&dataframe, &fingerprint));
+            if let Some(column) = &split_by {
+                write_split_csv(&dataframe, column, &outfile.unwrap(), quote_style, encoding.as_ref(), header.as_deref())?;
+            } else {
+                write_csv(&mut dataframe, &outfile.unwrap(), quote_style, encoding.as_ref(), header.as_deref())?;
+            }
+
+            let duration = start.elapsed();
+            timings.output = duration;
+            log::debug!(
+                "csv written in {} msec",
+                duration.as_millis().separate_with_commas()
+            );
+        } else {
+            let start = Instant::now();
+            if !assume_yes {
+                dataframe = confirm_table_rows(dataframe, max_table_rows);
+            }
+            if let Some(percent_metrics) = &humanize_display {
+                dataframe = googleads::humanize_for_display(&dataframe, percent_metrics)?;
+            }
+            println!("{}", dataframe);
+            timings.output = start.elapsed();
+        }
+    }
+
+    Ok((summary, timings))
+}
+
+/// Wall-clock time spent in each pipeline stage, reported by `--benchmark`
+#[derive(Default)]
+struct StageTimings {
+    auth: Duration,
+    account_resolution: Duration,
+    query: Duration,
+    groupby_first_pass: Duration,
+    merge: Duration,
+    groupby_second_pass: Duration,
+    output: Duration,
+}
+
+impl StageTimings {
+    /// Print timings to stderr, independent of log level
+    fn print(&self) {
+        let stages = [
+            ("auth", self.auth),
+            ("account resolution", self.account_resolution),
+            ("query", self.query),
+            ("1st pass groupby", self.groupby_first_pass),
+            ("merge", self.merge),
+            ("2nd pass groupby", self.groupby_second_pass),
+            ("output", self.output),
+        ];
+
+        eprintln!("Benchmark (msec):");
+        for (stage, duration) in stages {
+            eprintln!("  {stage:<20}{:>10}", duration.as_millis().separate_with_commas());
+        }
+    }
+}
+
+/// Append-only JSONL log of GAQL runs, for data-governance audits
+struct AuditLog {
+    path: String,
+    mcc_id: String,
+}
+
+/// One run in the audit log. Records what was queried, never the result data.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    run_id: &'a str,
+    query_fingerprint: &'a str,
+    timestamp: String,
+    user: String,
+    mcc_id: &'a str,
+    query: &'a str,
+    customer_ids: &'a [String],
+    total_rows: usize,
+    total_api_units: i64,
+    errored_accounts: usize,
+    incomplete_accounts: usize,
+}
+
+impl AuditLog {
+    fn append(
+        &self,
+        run_id: &str,
+        query_fingerprint: &str,
+        query: &str,
+        customer_ids: &[String],
+        summary: &RunSummary,
+    ) -> Result<()> {
+        let record = AuditRecord {
+            run_id,
+            query_fingerprint,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            user: env::var("USER")
+                .or_else(|_| env::var("USERNAME"))
+                .unwrap_or_else(|_| "unknown".to_owned()),
+            mcc_id: &self.mcc_id,
+            query,
+            customer_ids,
+            total_rows: summary.total_rows,
+            total_api_units: summary.api_units,
+            errored_accounts: summary.error_count(),
+            incomplete_accounts: summary.incomplete,
+        };
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Unable to open audit log {}", self.path))?;
+        writeln!(f, "{}", serde_json::to_string(&record)?)?;
+
+        Ok(())
+    }
+}
+
+/// Merged results of complete runs, saved as parquet for reuse by identical runs
+struct ResultsCache {
+    /// cache file, named by query fingerprint and per-account options
+    path: PathBuf,
+    /// max age of cached results to reuse
+    ttl: Duration,
+    /// ignore cached results, replacing them after querying
+    refresh: bool,
+}
+
+impl ResultsCache {
+    /// Cached results and the time they were fetched, if present and fresh
+    fn load(&self) -> Result<Option<(DataFrame, DateTime<Utc>)>> {
+        if self.refresh {
+            eprintln!("Results cache: refreshing (--no-cache)");
+            return Ok(None);
+        }
+
+        let modified = match fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("Results cache: miss");
+                return Ok(None);
+            }
+            Err(e) => bail!("Unable to read results cache {}: {e}", self.path.display()),
+        };
+        let age = modified.elapsed().unwrap_or_default();
+        if age > self.ttl {
+            eprintln!("Results cache: expired {} sec ago", (age - self.ttl).as_secs());
+            return Ok(None);
+        }
+
+        let df = ParquetReader::new(File::open(&self.path)?).finish()?;
+        eprintln!(
+            "Results cache: hit, reusing {} rows cached {} sec ago",
+            df.height().separate_with_commas(),
+            age.as_secs()
+        );
+        log::info!("Reusing cached results from {}", self.path.display());
+
+        Ok(Some((df, modified.into())))
+    }
+
+    /// Save results, with the file mtime set to when they were fetched
+    fn store(&self, df: &mut DataFrame, fetched_at: DateTime<Utc>) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(&self.path)?;
+        ParquetWriter::new(&mut file).finish(df)?;
+        file.set_modified(fetched_at.into())?;
+        log::debug!("Cached results in {}", self.path.display());
+
+        Ok(())
+    }
+}
+
+/// Tally of per-account outcomes for a GAQL run
+#[derive(Default)]
+struct RunSummary {
+    /// accounts that returned rows
+    with_rows: usize,
+    /// accounts that returned no rows
+    empty: usize,
+    /// customer_ids of errored accounts, by error kind
+    errors: BTreeMap<String, Vec<String>>,
+    /// accounts that did not complete, e.g. when interrupted
+    incomplete: usize,
+    /// rows returned across all accounts
+    total_rows: usize,
+    /// API units consumed across all accounts
+    api_units: i64,
+    /// customer_ids of accounts whose results were cut off at the per-account row cap
+    truncated: Vec<String>,
+    /// customer_ids of accounts retried by `--retry-on-empty` after returning no rows
+    empty_retried: Vec<String>,
+    /// the run hit `--timeout-overall` before every account completed
+    timed_out: bool,
+}
+
+impl RunSummary {
+    fn record_error(&mut self, kind: String, customer_id: String) {
+        self.errors.entry(kind).or_default().push(customer_id);
+    }
+
+    fn error_count(&self) -> usize {
+        self.errors.values().map(Vec::len).sum()
+    }
+
+    /// true if every account completed without error or truncation
+    fn is_complete(&self) -> bool {
+        self.error_count() == 0 && self.incomplete == 0 && self.truncated.is_empty()
+    }
+
+    /// Print summary to stderr so it does not mix with query results on stdout
+    fn print(&self) {
+        let total = self.with_rows + self.empty + self.error_count() + self.incomplete;
+        let msg = format!(
+            "Queried {} accounts: {} returned rows, {} returned no rows, {} errored, {} incomplete",
+            total.separate_with_commas(),
+            self.with_rows.separate_with_commas(),
+            self.empty.separate_with_commas(),
+            self.error_count().separate_with_commas(),
+            self.incomplete.separate_with_commas()
+        );
+        log::info!("{msg}");
+        eprintln!("{msg}");
+
+        for (kind, customer_ids) in &self.errors {
+            let msg = format!(
+                "  {kind}: {} accounts ({})",
+                customer_ids.len().separate_with_commas(),
+                customer_ids.join(", ")
+            );
+            log::info!("{msg}");
+            eprintln!("{msg}");
+        }
+
+        if !self.truncated.is_empty() {
+            let msg = format!(
+                "  Truncated at row cap: {} accounts ({})",
+                self.truncated.len().separate_with_commas(),
+                self.truncated.join(", ")
+            );
+            log::warn!("{msg}");
+            eprintln!("{msg}");
+        }
+
+        if !self.empty_retried.is_empty() {
+            let msg = format!(
+                "  Retried on empty: {} accounts ({})",
+                self.empty_retried.len().separate_with_commas(),
+                self.empty_retried.join(", ")
+            );
+            log::info!("{msg}");
+            eprintln!("{msg}");
+        }
+    }
+}
+
+/// Load `--query-map` queries, rewritten like the default query: with the same `--param` values and date window.
+/// Accounts with their own query need the same columns as the rest, so results can be merged.
+fn load_query_map(
+    path: &str,
+    args: &args::Cli,
+    config: &config::MyConfig,
+    date_conditions: &[String],
+) -> Result<HashMap<String, String>> {
+    let mut query_map = util::get_query_map_from_file(path)?;
+    let Some(query) = args.gaql_query.as_deref().filter(|_| !query_map.is_empty()) else {
+        return Ok(query_map);
+    };
+
+    let fields = util::get_select_fields(query);
+    let max_query_length = config
+        .max_query_length
+        .unwrap_or(googleads::DEFAULT_MAX_QUERY_LENGTH);
+    for (customer_id, mapped_query) in query_map.iter_mut() {
+        if !args.param.is_empty() {
+            *mapped_query = util::apply_query_params(mapped_query, &args.param)
+                .with_context(|| format!("query for account {customer_id}"))?;
+        }
+        for condition in date_conditions {
+            *mapped_query = util::add_where_condition(mapped_query, condition);
+        }
+
+        if util::get_select_fields(mapped_query) != fields {
+            bail!("query for account {customer_id} does not select the same fields, in the same order, as the default query");
+        }
+        if mapped_query.len() > max_query_length {
+            bail!(
+                "query for account {customer_id} exceeds max query length of {} chars",
+                max_query_length.separate_with_commas()
+            );
+        }
+        if !args.field_service {
+            *mapped_query = util::add_parameter(mapped_query, "omit_unselected_resource_names", "true");
+        }
+    }
+
+    Ok(query_map)
+}
+
+/// Whether `--date-field` is a DATE or DATETIME field, exiting if it is neither.
+/// Only resolved when a date window is applied, so an unused `--date-field` is not checked.
+fn date_field_kind(field: &str) -> googleads::TemporalKind {
+    match googleads::temporal_kind(field, &googleads::read_field_cache()) {
+        Some(kind) => kind,
+        None => util::fail(format!("--date-field {field} is not a DATE or DATETIME field")),
+    }
+}
+
+/// WHERE condition comparing a date field to a date. DATETIME fields compare from the start of day
+/// for lower bounds, and to the end of day for upper bounds, so both ends are inclusive.
+fn date_condition(field: &str, kind: googleads::TemporalKind, op: &str, date: NaiveDate) -> String {
+    let date = date.format(util::DATE_FORMAT);
+    match (kind, op) {
+        (googleads::TemporalKind::Date, _) => format!("{field} {op} '{date}'"),
+        (googleads::TemporalKind::DateTime, "<=") => format!("{field} {op} '{date} 23:59:59'"),
+        (googleads::TemporalKind::DateTime, _) => format!("{field} {op} '{date} 00:00:00'"),
+    }
+}
+
+/// Fields to look up in GoogleAdsFieldService before querying. Metadata is only needed to check the `--date-field`
+/// of a date window and for `--smart-column-order`; otherwise the field cache and name rules type columns.
+fn metadata_fields(query: &str, date_field: Option<&str>, smart_column_order: bool) -> Vec<String> {
+    let mut fields = if smart_column_order {
+        util::get_select_fields(query)
+    } else {
+        Vec::new()
+    };
+    fields.extend(date_field.map(str::to_owned));
+    fields
+}
+
+/// Check `--groupby` and `--sortby` columns are selected by the query, or added by other options.
+/// After grouping, only groupby, metric and account count columns remain to sort by.
+fn check_groupby_columns(query: &str, args: &args::Cli, extra_columns: &[&str]) -> Result<()> {
+    let fields = util::get_select_fields(query);
+    if fields.is_empty() {
+        return Ok(());
+    }
+
+    let is_available = |column: &str| {
+        fields.iter().any(|f| f == column)
+            || extra_columns.contains(&column)
+            || (args.reconstruct_resource_names && column.ends_with(".resource_name"))
+    };
+
+    for column in &args.groupby {
+        if !is_available(column) {
+            bail!("--groupby column '{column}' is not in the query's SELECT clause");
+        }
+    }
+
+    for column in &args.sortby {
+        if !is_available(column) {
+            bail!("--sortby column '{column}' is not in the query's SELECT clause");
+        }
+        let is_kept_by_grouping = column.starts_with("metrics.")
+            || args.groupby.contains(column)
+            || (args.count_accounts_per_group && column == ACCOUNT_COUNT_COLUMN);
+        if !args.groupby.is_empty() && !is_kept_by_grouping {
+            bail!("--sortby column '{column}' must be a --groupby column or a metric when grouping");
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply groupby and aggregation to dataframe
+/// groupby_cols: columns to group by; cannot be a subset of agg_cols
+/// agg_cols: columns to aggregate
+/// count_accounts: add an account count per group. A single account's rows count once per group
+/// on the 1st pass, and counts from 1st pass results are summed on the 2nd pass
+async fn apply_groupby(df: DataFrame, groupby_cols: Vec<String>, agg_cols: Vec<String>, aggs: Vec<(String, Aggregation)>, sortby_cols: Vec<String>, count_accounts: bool) -> Result<DataFrame> {
+
+    let has_account_count = df.get_column_names().contains(&ACCOUNT_COUNT_COLUMN);
+
+    // if no sortby columns provided, use groupby columns
+    let sortby_cols_ref = if sortby_cols.is_empty() {
+        &groupby_cols
+    } else {
+        &sortby_cols
+    };
+
+    // apply groupby/aggregation as needed
+    // if both sortby and groupby are empty, just returns original dataframe
+    let df_agg = if groupby_cols.is_empty() {
+        df.lazy()
+    } else {
+        let mut agg_exprs: Vec<Expr> = agg_cols.iter().map(|col_name| aggregate(col_name, &aggs)).collect();
+        let mut lf = df.lazy();
+        if count_accounts {
+            if has_account_count {
+                agg_exprs.push(col(ACCOUNT_COUNT_COLUMN).sum());
+            } else {
+                lf = lf.with_column(lit(1u32).alias(ACCOUNT_COUNT_COLUMN));
+                agg_exprs.push(col(ACCOUNT_COUNT_COLUMN).max());
+            }
+        }
+        lf.group_by(&groupby_cols.iter().map(String::as_str).collect::<Vec<_>>())
+            .agg(&agg_exprs)
+    }
+    .sort(
+        sortby_cols_ref,
+        SortMultipleOptions {
+            descending: vec![true; sortby_cols_ref.len()],
+            ..Default::default()
+        }
+    )
+    .collect()?;
+
+    Ok(df_agg)
+}
+
+/// How `--groupby` aggregates a metric column
+#[derive(Clone, Debug)]
+enum Aggregation {
+    Sum,
+    Min,
+    Max,
+    /// mean weighted by another (summed) column, e.g. CTR weighted by impressions
+    WeightedMean(String),
+}
+
+/// Parse an `--agg` spec of the form col=fn, where fn is sum, min, max or wmean:<weight col>
+fn parse_agg(spec: &str) -> Result<(String, Aggregation)> {
+    let Some((column, func)) = spec.split_once('=') else {
+        bail!("'{spec}' is not of the form col=fn");
+    };
+    if column.is_empty() {
+        bail!("'{spec}' is missing a column name");
+    }
+
+    let aggregation = match func.split_once(':') {
+        None if func == "sum" => Aggregation::Sum,
+        None if func == "min" => Aggregation::Min,
+        None if func == "max" => Aggregation::Max,
+        Some(("wmean", weight)) if !weight.is_empty() => Aggregation::WeightedMean(weight.to_string()),
+        _ => bail!("unsupported aggregation '{func}' in '{spec}', expected one of sum, min, max, wmean:<weight col>"),
+    };
+
+    Ok((column.to_string(), aggregation))
+}
+
+/// Check `--agg` columns are metrics, and weights are numeric metrics that are summed.
+/// Summed weights keep weighted means correct across the per-account and global groupby passes.
+fn check_aggs(aggs: &[(String, Aggregation)], df: &DataFrame, metrics_cols: &[String]) -> Result<()> {
+    for (column, aggregation) in aggs {
+        if !metrics_cols.contains(column) {
+            bail!("--agg column '{column}' is not a metric column in results");
+        }
+
+        if let Aggregation::WeightedMean(weight) = aggregation {
+            if !metrics_cols.contains(weight) {
+                bail!("--agg weight column '{weight}' for '{column}' is not a metric column in results");
+            }
+            if !df.column(weight)?.dtype().is_numeric() {
+                bail!("--agg weight column '{weight}' for '{column}' must be numeric");
+            }
+            if aggs.iter().any(|(c, a)| c == weight && !matches!(a, Aggregation::Sum)) {
+                bail!("--agg weight column '{weight}' for '{column}' must be aggregated with sum");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Aggregation expression for a metric column, summing unless overridden by `--agg`
+fn aggregate(column: &str, aggs: &[(String, Aggregation)]) -> Expr {
+    match aggs.iter().find(|(c, _)| c == column).map(|(_, a)| a) {
+        None | Some(Aggregation::Sum) => col(column).sum(),
+        Some(Aggregation::Min) => col(column).min(),
+        Some(Aggregation::Max) => col(column).max(),
+        Some(Aggregation::WeightedMean(weight)) => {
+            (col(column).cast(DataType::Float64) * col(weight).cast(DataType::Float64)).sum()
+                / col(weight).cast(DataType::Float64).sum()
         }
+    }
+}
 
-        let duration = start.elapsed();
-        log::debug!(
-            "merged {:#} dataframes in {} msec",
-            len,
-            duration.as_millis().separate_with_commas()
-        );
+/// Parse a `--cast` spec of the form col:type
+fn parse_cast(spec: &str) -> Result<(String, DataType)> {
+    let Some((column, type_name)) = spec.rsplit_once(':') else {
+        bail!("'{spec}' is not of the form col:type");
+    };
+    if column.is_empty() {
+        bail!("'{spec}' is missing a column name");
+    }
 
-        // apply 2nd pass gropuby/sortby
-        if !groupby.is_empty() || !sortby.is_empty() {
-            let start = Instant::now();
+    let dtype = match type_name {
+        "str" => DataType::String,
+        "i64" => DataType::Int64,
+        "f64" => DataType::Float64,
+        "bool" => DataType::Boolean,
+        _ => bail!("unsupported type '{type_name}' in '{spec}', expected one of str, i64, f64, bool"),
+    };
 
-            log::info!("Applying global groupby with columns: {:?}", groupby);
-            log::info!("Applying global sortby with columns: {:?}", sortby);
+    Ok((column.to_string(), dtype))
+}
 
-            dataframe = apply_groupby(dataframe, groupby.clone(), metrics_cols.clone().unwrap(), sortby.clone()).await?;
+/// Cast columns to user-specified types, failing if any value cannot be converted
+fn apply_casts(df: &mut DataFrame, casts: &[(String, DataType)]) -> Result<()> {
+    for (column, dtype) in casts {
+        let Some(idx) = df.get_column_index(column) else {
+            bail!("--cast column '{column}' not found in results");
+        };
 
-            let duration = start.elapsed();
-            log::debug!(
-                "applied 2nd pass groupby in {} msec",
-                duration.as_millis().separate_with_commas()
+        let series = &df.get_columns()[idx];
+        let casted = series
+            .strict_cast(dtype)
+            .with_context(|| format!("Unable to cast column '{column}' from {} to {dtype}", series.dtype()))?;
+
+        log::debug!("Cast column '{column}' to {dtype}");
+        df.replace_column(idx, casted)?;
+    }
+
+    Ok(())
+}
+
+/// Write one CSV per distinct value of `column`, named `<stem>_<value>.<ext>` next to `outfile`
+fn write_split_csv(
+    df: &DataFrame,
+    column: &str,
+    outfile: &str,
+    quote_style: QuoteStyle,
+    encoding: Option<&OutputEncoding>,
+    header: Option<&str>,
+) -> Result<()> {
+    if df.get_column_index(column).is_none() {
+        bail!("--split-by column '{column}' not found in results");
+    }
+
+    let partitions = df.partition_by_stable([column], true)?;
+    if partitions.len() > SPLIT_BY_WARN_FILES {
+        log::warn!(
+            "--split-by column '{column}' has {} distinct values, writing that many files",
+            partitions.len().separate_with_commas()
+        );
+    }
+
+    let path = Path::new(outfile);
+    let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+    let extension = path.extension().map(|e| e.to_string_lossy()).unwrap_or("csv".into());
+
+    let mut filenames: HashSet<PathBuf> = HashSet::new();
+    for mut partition in partitions {
+        let value = partition.column(column)?.str_value(0)?;
+        let filename = path.with_file_name(format!(
+            "{stem}_{}.{extension}",
+            util::sanitize_filename(&value)
+        ));
+        if !filenames.insert(filename.clone()) {
+            bail!(
+                "--split-by values of '{column}' map to the same file {}",
+                filename.display()
             );
         }
 
-        log::debug!("final dataframe shape: {:?}", dataframe.shape());
+        write_csv(&mut partition, &filename.to_string_lossy(), quote_style, encoding, header)?;
+    }
 
-        if outfile.is_some() {
-            let start = Instant::now();
+    log::info!(
+        "Wrote {} files split by '{column}'",
+        filenames.len().separate_with_commas()
+    );
 
-            write_csv(&mut dataframe, &outfile.unwrap())?;
+    Ok(())
+}
 
-            let duration = start.elapsed();
-            log::debug!(
-                "csv written in {} msec",
-                duration.as_millis().separate_with_commas()
-            );
+/// Load a local CSV or Parquet file for `--merge-with`, checking it has the key columns
+fn load_local_dataset(path: &str, keys: &[String]) -> Result<DataFrame> {
+    let df = if Path::new(path).extension().is_some_and(|e| e == "parquet") {
+        ParquetReader::new(File::open(path)?).finish()?
+    } else {
+        CsvReadOptions::default()
+            .with_has_header(true)
+            .try_into_reader_with_file_path(Some(path.into()))?
+            .finish()?
+    };
+
+    if keys.is_empty() {
+        bail!("--merge-with requires --on key columns");
+    }
+    for key in keys {
+        if df.get_column_index(key).is_none() {
+            bail!("key column '{key}' not found in {path}");
+        }
+    }
+
+    if df.select(keys)?.is_duplicated()?.any() {
+        log::warn!("--merge-with file {path} has duplicate keys, so matching result rows will be repeated");
+    }
+
+    log::debug!("Loaded {} rows to merge with from {path}", df.height().separate_with_commas());
+
+    Ok(df)
+}
+
+/// Left join a local dataset onto results. Result rows without a match get nulls,
+/// and local columns whose names collide with result columns get a `_local` suffix.
+fn join_local_dataset(df: DataFrame, local: &DataFrame, keys: &[String]) -> Result<DataFrame> {
+    // local keys are cast to match results, e.g. CustomerIDs read from CSV as integers
+    let mut local = local.clone();
+    for key in keys {
+        let Ok(column) = df.column(key) else {
+            bail!("--on key column '{key}' not found in results");
+        };
+        let dtype = column.dtype().clone();
+        let idx = local.try_get_column_index(key)?;
+        let casted = local.get_columns()[idx]
+            .strict_cast(&dtype)
+            .with_context(|| format!("Unable to match --merge-with key '{key}' to results type {dtype}"))?;
+        local.replace_column(idx, casted)?;
+    }
+
+    let on: Vec<Expr> = keys.iter().map(|k| col(k)).collect();
+    let df = df
+        .lazy()
+        .join_builder()
+        .with(local.lazy())
+        .left_on(on.clone())
+        .right_on(on)
+        .how(JoinType::Left)
+        .suffix("_local")
+        .finish()
+        .collect()?;
+
+    Ok(df)
+}
+
+/// Order GAQL field columns as resource attributes, then segments, then metrics, alphabetically within each.
+/// Columns are classified by their category in the field cache, or by name prefix when not cached.
+/// Other columns, e.g. `customer_id` or `fetched_at`, keep their positions.
+fn smart_column_order_of(columns: &[&str], field_cache: &googleads::FieldCache) -> Vec<String> {
+    let category = |c: &str| {
+        if let Some(metadata) = field_cache.get(c) {
+            match metadata.category.as_str() {
+                "Resource" | "Attribute" => Some(0),
+                "Segment" => Some(1),
+                "Metric" => Some(2),
+                _ => None,
+            }
+        } else if c.starts_with("metrics.") {
+            Some(2)
+        } else if c.starts_with("segments.") {
+            Some(1)
+        } else if c.contains('.') {
+            Some(0)
         } else {
-            println!("{}", dataframe);
+            None
+        }
+    };
+
+    let mut fields: Vec<&str> = columns.iter().copied().filter(|c| category(c).is_some()).collect();
+    fields.sort_by_key(|c| (category(c), *c));
+    let mut fields = fields.into_iter();
+
+    columns
+        .iter()
+        .map(|c| match category(c) {
+            Some(_) => fields.next().unwrap_or(c),
+            None => c,
+        })
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Rename output columns to report labels. Renames of absent columns, or to names already taken, are skipped with a warning.
+fn rename_columns(df: &mut DataFrame, renames: &[(String, String)]) -> Result<()> {
+    for (from, to) in renames {
+        if df.get_column_index(from).is_none() {
+            log::warn!("--rename-map column {from} is not in results");
+            continue;
+        }
+        if from == to {
+            continue;
         }
+        if df.get_column_index(to).is_some() {
+            log::warn!("Not renaming {from}, since column {to} already exists");
+            continue;
+        }
+        df.rename(from, to)?;
     }
 
     Ok(())
 }
 
-/// Apply groupby and aggregation to dataframe
-/// groupby_cols: columns to group by; cannot be a subset of agg_cols
-/// agg_cols: columns to aggregate
-async fn apply_groupby(df: DataFrame, groupby_cols: Vec<String>, agg_cols: Vec<String>, sortby_cols: Vec<String>) -> Result<DataFrame> {
+/// Remove a `<resource>.` prefix from output column names, e.g. `campaign.name` to `name`.
+/// Columns are left as is where the short name is already taken.
+/// Returns the number of columns with the prefix.
+fn strip_column_prefix(df: &mut DataFrame, resource: &str) -> Result<usize> {
+    let prefix = format!("{}.", resource.trim_end_matches('.'));
+    let columns: Vec<String> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| c.starts_with(&prefix))
+        .map(str::to_owned)
+        .collect();
 
-    // if no sortby columns provided, use groupby columns
-    let sortby_cols_ref = if sortby_cols.is_empty() {
-        &groupby_cols
-    } else {
-        &sortby_cols
+    for column in &columns {
+        let short_name = &column[prefix.len()..];
+        if df.get_column_index(short_name).is_some() {
+            log::warn!("Not stripping prefix from {column}, since column {short_name} already exists");
+            continue;
+        }
+        df.rename(column, short_name)?;
+    }
+
+    Ok(columns.len())
+}
+
+/// Prepend a column with the customer_id rows were fetched from
+fn add_customer_id_column(df: &mut DataFrame, column_name: &str, customer_id: &str) -> Result<()> {
+    df.insert_column(0, Series::new(column_name, vec![customer_id; df.height()]))?;
+
+    Ok(())
+}
+
+/// Append a column with the run's fetch timestamp, the same value on every row
+fn add_fetched_at_column(df: &mut DataFrame, column_name: &str, fetched_at: &str) -> Result<()> {
+    df.with_column(Series::new(column_name, vec![fetched_at; df.height()]))?;
+
+    Ok(())
+}
+
+/// Drop rows where every metric column is zero or null, e.g. resources with no activity
+fn drop_zero_metric_rows(df: DataFrame) -> Result<DataFrame> {
+    let has_activity = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| c.starts_with("metrics."))
+        // integer metrics are i64 and others f64, so compare both as floats
+        .map(|c| col(c).cast(DataType::Float64).fill_null(lit(0.0)).neq(lit(0.0)))
+        .reduce(|acc, e| acc.or(e));
+
+    let Some(has_activity) = has_activity else {
+        bail!("--prune-zero-rows needs at least one metric column in results");
     };
 
-    // apply groupby/aggregation as needed
-    // if both sortby and groupby are empty, just returns original dataframe
-    let df_agg = if groupby_cols.is_empty() {
-        df.lazy()
+    let before = df.height();
+    let df = df.lazy().filter(has_activity).collect()?;
+    log::debug!(
+        "Pruned {} rows with all-zero metrics",
+        (before - df.height()).separate_with_commas()
+    );
+
+    Ok(df)
+}
+
+/// Column holding the `--compare-accounts` metric, which `--humanize-metrics` may have renamed
+fn compare_metric_column(df: &DataFrame, metric: &str, humanize_percent_metrics: Option<&[String]>) -> String {
+    if df.get_column_index(metric).is_none() {
+        if let Some((humanized, _)) = humanize_percent_metrics.and_then(|p| googleads::humanized_metric(metric, p)) {
+            return humanized;
+        }
+    }
+    metric.to_owned()
+}
+
+/// Pivot a metric so each account is a column, with the remaining dimension columns as rows.
+/// `per_account` columns, such as account labels, differ between accounts, so are left out of the rows
+fn compare_accounts(df: &DataFrame, customer_id_column: &str, metric: &str, per_account: &[&str]) -> Result<DataFrame> {
+    match df.column(metric) {
+        Ok(series) if series.dtype().is_numeric() => {}
+        Ok(series) => bail!("--compare-accounts metric '{metric}' must be numeric, but is {}", series.dtype()),
+        Err(_) => bail!("--compare-accounts metric '{metric}' not found in results"),
+    }
+
+    let index: Vec<&str> = df
+        .get_column_names()
+        .into_iter()
+        .filter(|c| *c != customer_id_column && !c.starts_with("metrics.") && !per_account.contains(c))
+        .collect();
+    if index.is_empty() {
+        bail!("--compare-accounts needs at least one non-metric column to compare accounts on");
+    }
+
+    let df = pivot_stable(
+        df,
+        [customer_id_column],
+        Some(index),
+        Some([metric]),
+        false,
+        Some(col(metric).first()),
+        None,
+    )?;
+
+    Ok(df)
+}
+
+/// Count rows per equal-width bucket of a numeric column, between its min and max.
+/// The last bucket includes the max; null values are not counted.
+fn histogram(df: &DataFrame, column: &str, buckets: usize) -> Result<DataFrame> {
+    let series = df
+        .column(column)
+        .with_context(|| format!("--histogram column '{column}' not found in results"))?;
+    if !series.dtype().is_numeric() {
+        bail!(
+            "--histogram column '{column}' must be numeric, but is {}",
+            series.dtype()
+        );
+    }
+
+    let values: Vec<f64> = series
+        .cast(&DataType::Float64)?
+        .f64()?
+        .into_no_null_iter()
+        .collect();
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / buckets as f64;
+
+    // no buckets when there are no values to bound them
+    let buckets = if values.is_empty() { 0 } else { buckets };
+
+    let mut counts = vec![0u32; buckets];
+    for v in &values {
+        let bucket = if width > 0.0 {
+            (((v - min) / width) as usize).min(buckets - 1)
+        } else {
+            0
+        };
+        counts[bucket] += 1;
+    }
+
+    let (lower, upper): (Vec<f64>, Vec<f64>) = (0..buckets)
+        .map(|i| (min + width * i as f64, min + width * (i + 1) as f64))
+        .unzip();
+
+    let df = DataFrame::new(vec![
+        Series::new("bucket_lower", lower),
+        Series::new("bucket_upper", upper),
+        Series::new("count", counts),
+    ])?;
+
+    Ok(df)
+}
+
+/// Ask before flooding a terminal with a large table. Polars truncates tables itself unless
+/// POLARS_FMT_MAX_ROWS raises the limit, so only that case can exceed `max_rows`.
+/// Returns the DataFrame to print, truncated to `max_rows` if declined or if no one can answer.
+fn confirm_table_rows(df: DataFrame, max_rows: usize) -> DataFrame {
+    let displayed_rows = match env::var("POLARS_FMT_MAX_ROWS").map(|v| v.parse::<i64>()) {
+        Ok(Ok(n)) if n < 0 => df.height(),
+        Ok(Ok(n)) => df.height().min(n as usize),
+        _ => return df,
+    };
+    if displayed_rows <= max_rows || !io::stdout().is_terminal() {
+        return df;
+    }
+
+    let confirmed = io::stdin().is_terminal() && {
+        eprint!(
+            "About to print {} rows. Print all? [y/N] ",
+            displayed_rows.separate_with_commas()
+        );
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
+    };
+
+    if confirmed {
+        df
     } else {
-        df.lazy()
-            .group_by(&groupby_cols.iter().map(String::as_str).collect::<Vec<_>>())
-            .agg(&agg_cols.iter().map(|col_name| col(col_name).sum()).collect::<Vec<_>>())
+        eprintln!(
+            "Showing first {} of {} rows. Use --output to write all rows, or --yes to print them.",
+            max_rows.separate_with_commas(),
+            df.height().separate_with_commas()
+        );
+        df.head(Some(max_rows))
     }
-    .sort(
-        sortby_cols_ref,
-        SortMultipleOptions {
-            descending: vec![true; sortby_cols_ref.len()],
-            ..Default::default()
-        }
-    )
-    .collect()?;
+}
 
-    Ok(df_agg)
+/// Map `--quote-style` value to polars' CSV quoting.
+/// `never` can emit ambiguous CSV when a value contains the separator or a quote.
+fn quote_style(style: &str) -> QuoteStyle {
+    match style {
+        "always" => QuoteStyle::Always,
+        "non_numeric" => QuoteStyle::NonNumeric,
+        "never" => QuoteStyle::Never,
+        _ => QuoteStyle::Necessary,
+    }
+}
+
+/// Non-UTF-8 encoding for CSV files, for legacy consumers
+struct OutputEncoding {
+    encoding: &'static Encoding,
+    /// encoded replacement for characters the encoding can't represent
+    placeholder: Vec<u8>,
+}
+
+/// Look up an `--encoding` label, returning None for UTF-8 which needs no transcoding
+fn output_encoding(label: &str, placeholder: &str) -> Result<Option<OutputEncoding>> {
+    let Some(encoding) = Encoding::for_label(label.as_bytes()) else {
+        bail!("unknown encoding '{label}'");
+    };
+    if encoding == UTF_8 {
+        return Ok(None);
+    }
+
+    let (placeholder_bytes, _, unmappable) = encoding.encode(placeholder);
+    if unmappable {
+        bail!("placeholder '{placeholder}' cannot be represented in {}", encoding.name());
+    }
+
+    Ok(Some(OutputEncoding {
+        encoding,
+        placeholder: placeholder_bytes.into_owned(),
+    }))
+}
+
+/// Comment lines describing the API version, query fingerprint and column types of results, for `--embed-schema-metadata`
+fn schema_metadata(df: &DataFrame, fingerprint: &str) -> String {
+    let columns: Vec<String> = df
+        .schema()
+        .iter()
+        .map(|(name, dtype)| format!("{name}:{dtype}"))
+        .collect();
+    format!(
+        "# api_version: {}\n# query_fingerprint: {fingerprint}\n# columns: {}\n",
+        googleads::API_VERSION,
+        columns.join(", ")
+    )
 }
 
-fn write_csv(df: &mut DataFrame, outfile: &str) -> Result<()> {
-    let f = File::create(outfile)?;
-    CsvWriter::new(f).finish(df)?;
+/// Write CSV, preceded by header text such as comment lines, if any
+fn write_csv(
+    df: &mut DataFrame,
+    outfile: &str,
+    quote_style: QuoteStyle,
+    encoding: Option<&OutputEncoding>,
+    header: Option<&str>,
+) -> Result<()> {
+    let Some(encoding) = encoding else {
+        let mut f = File::create(outfile)?;
+        if let Some(header) = header {
+            f.write_all(header.as_bytes())?;
+        }
+        CsvWriter::new(f).with_quote_style(quote_style).finish(df)?;
+        return Ok(());
+    };
+
+    let mut buffer: Vec<u8> = header.unwrap_or_default().as_bytes().to_vec();
+    CsvWriter::new(&mut buffer).with_quote_style(quote_style).finish(df)?;
+    let (encoded, replaced) = util::transcode(
+        &String::from_utf8(buffer)?,
+        encoding.encoding,
+        &encoding.placeholder,
+    );
+    if replaced > 0 {
+        log::warn!(
+            "Replaced {} characters not representable in {} in {outfile}",
+            replaced.separate_with_commas(),
+            encoding.encoding.name()
+        );
+    }
+    fs::write(outfile, encoded)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn account_count_is_distinct_accounts_per_group() {
+        let groupby = vec!["segments.device".to_owned()];
+        let metrics = vec!["metrics.clicks".to_owned()];
+        let account_a = df!(
+            "segments.device" => ["MOBILE", "MOBILE", "DESKTOP"],
+            "metrics.clicks" => [1u64, 2, 3]
+        )
+        .unwrap();
+        let account_b = df!(
+            "segments.device" => ["MOBILE"],
+            "metrics.clicks" => [4u64]
+        )
+        .unwrap();
+
+        // 1st pass per account, 2nd pass over merged results, as in gaql_query_async
+        let mut merged = DataFrame::default();
+        for df in [account_a, account_b] {
+            let grouped = apply_groupby(df, groupby.clone(), metrics.clone(), Vec::new(), Vec::new(), true)
+                .await
+                .unwrap();
+            merged = if merged.width() == 0 { grouped } else { merged.vstack(&grouped).unwrap() };
+        }
+        let df = apply_groupby(merged, groupby, metrics, Vec::new(), Vec::new(), true)
+            .await
+            .unwrap();
+
+        let devices: Vec<&str> = df.column("segments.device").unwrap().str().unwrap().into_no_null_iter().collect();
+        let counts = df.column(ACCOUNT_COUNT_COLUMN).unwrap().cast(&DataType::Int64).unwrap();
+        let clicks = df.column("metrics.clicks").unwrap().cast(&DataType::Int64).unwrap();
+        assert_eq!(devices, ["MOBILE", "DESKTOP"]);
+        assert_eq!(counts.i64().unwrap().into_no_null_iter().collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(clicks.i64().unwrap().into_no_null_iter().collect::<Vec<_>>(), [7, 3]);
+    }
+
+    #[test]
+    fn sortby_account_count_when_counting_accounts() {
+        let query = "SELECT segments.device, metrics.clicks FROM campaign";
+        let args = args::Cli::parse_from([
+            "mcc-gaql",
+            "--groupby",
+            "segments.device",
+            "--count-accounts-per-group",
+            "--sortby",
+            ACCOUNT_COUNT_COLUMN,
+        ]);
+
+        assert!(check_groupby_columns(query, &args, &[ACCOUNT_COUNT_COLUMN]).is_ok());
+        assert!(check_groupby_columns(query, &args, &[]).is_err());
+    }
+
+    #[test]
+    fn results_cache_keeps_fetch_time() {
+        let cache = ResultsCache {
+            path: std::env::temp_dir().join(format!("mcc-gaql-test-{}-results.parquet", std::process::id())),
+            ttl: Duration::from_secs(3600),
+            refresh: false,
+        };
+        let fetched_at = Utc::now() - chrono::Duration::minutes(10);
+        let mut df = df!("metrics.clicks" => [1u64, 2]).unwrap();
+
+        cache.store(&mut df, fetched_at).unwrap();
+        let (cached, cached_at) = cache.load().unwrap().unwrap();
+        fs::remove_file(&cache.path).unwrap();
+
+        assert_eq!(cached, df);
+        assert_eq!(cached_at.timestamp(), fetched_at.timestamp());
+    }
+
+    #[test]
+    fn date_window_on_non_segments_field() {
+        let since = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let until = NaiveDate::from_ymd_opt(2024, 1, 31).unwrap();
+
+        let query = "SELECT campaign.id FROM campaign WHERE campaign.status = 'ENABLED' LIMIT 10";
+        let query = util::add_where_condition(
+            query,
+            &date_condition("campaign.start_date", googleads::TemporalKind::Date, ">=", since),
+        );
+        let query = util::add_where_condition(
+            &query,
+            &date_condition("campaign.start_date", googleads::TemporalKind::Date, "<=", until),
+        );
+        assert_eq!(
+            query,
+            "SELECT campaign.id FROM campaign WHERE campaign.status = 'ENABLED' \
+             AND campaign.start_date >= '2024-01-01' AND campaign.start_date <= '2024-01-31' LIMIT 10"
+        );
+
+        let query = "SELECT change_event.change_date_time FROM change_event";
+        let query = util::add_where_condition(
+            query,
+            &date_condition("change_event.change_date_time", googleads::TemporalKind::DateTime, ">=", since),
+        );
+        let query = util::add_where_condition(
+            &query,
+            &date_condition("change_event.change_date_time", googleads::TemporalKind::DateTime, "<=", until),
+        );
+        assert_eq!(
+            query,
+            "SELECT change_event.change_date_time FROM change_event \
+             WHERE change_event.change_date_time >= '2024-01-01 00:00:00' \
+             AND change_event.change_date_time <= '2024-01-31 23:59:59'"
+        );
+    }
+
+    #[test]
+    fn plain_query_needs_no_field_metadata() {
+        let query = "SELECT campaign.name, segments.date, metrics.clicks FROM campaign";
+
+        // no FieldService lookup without a date window or --smart-column-order
+        assert!(metadata_fields(query, None, false).is_empty());
+        assert_eq!(metadata_fields(query, Some("segments.date"), false), ["segments.date"]);
+        assert_eq!(
+            metadata_fields(query, None, true),
+            ["campaign.name", "segments.date", "metrics.clicks"]
+        );
+    }
+
+    #[test]
+    fn smart_column_order_groups_by_category() {
+        let field_metadata = |category: &str| googleads::FieldMetadata {
+            category: category.to_owned(),
+            data_type: "String".to_owned(),
+            filterable: true,
+        };
+        let field_cache = googleads::FieldCache::from([
+            ("campaign.name".to_owned(), field_metadata("Attribute")),
+            ("customer.id".to_owned(), field_metadata("Attribute")),
+            ("segments.device".to_owned(), field_metadata("Segment")),
+            ("metrics.clicks".to_owned(), field_metadata("Metric")),
+            ("metrics.cost_micros".to_owned(), field_metadata("Metric")),
+            // cached, but in no category to order by
+            ("ad_group_criterion.keyword.text".to_owned(), field_metadata("Unspecified")),
+        ]);
+        let columns = [
+            "metrics.cost_micros",
+            "customer_id",
+            "segments.device",
+            "metrics.clicks",
+            "ad_group_criterion.keyword.text",
+            "campaign.name",
+            "customer.id",
+        ];
+
+        assert_eq!(
+            smart_column_order_of(&columns, &field_cache),
+            [
+                "campaign.name",
+                "customer_id",
+                "customer.id",
+                "segments.device",
+                "ad_group_criterion.keyword.text",
+                "metrics.clicks",
+                "metrics.cost_micros",
+            ]
+        );
+
+        // without metadata, categories come from name prefixes
+        assert_eq!(
+            smart_column_order_of(&columns, &googleads::FieldCache::new()),
+            [
+                "ad_group_criterion.keyword.text",
+                "customer_id",
+                "campaign.name",
+                "customer.id",
+                "segments.device",
+                "metrics.clicks",
+                "metrics.cost_micros",
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_zero_metric_rows_keeps_rows_with_activity() {
+        let df = df!(
+            "campaign.name" => ["zero", "clicks", "ctr", "null", "null and zero"],
+            "metrics.clicks" => [Some(0i64), Some(3), Some(0), None, None],
+            "metrics.ctr" => [Some(0.0), Some(0.0), Some(0.5), None, Some(0.0)]
+        )
+        .unwrap();
+
+        let df = drop_zero_metric_rows(df).unwrap();
+
+        let names: Vec<Option<&str>> = df.column("campaign.name").unwrap().str().unwrap().into_iter().collect();
+        assert_eq!(names, [Some("clicks"), Some("ctr")]);
+    }
+
+    #[test]
+    fn compare_accounts_excludes_per_account_columns() {
+        let df = df!(
+            "customer_id" => ["1111111111", "2222222222", "1111111111", "2222222222"],
+            "customer_name" => ["Shop A", "Shop B", "Shop A", "Shop B"],
+            "segments.device" => ["MOBILE", "MOBILE", "DESKTOP", "DESKTOP"],
+            "metrics.clicks" => [1i64, 2, 3, 4],
+            "fetched_at" => ["2024-01-02T03:04:05Z"; 4]
+        )
+        .unwrap();
+
+        let df = compare_accounts(&df, "customer_id", "metrics.clicks", &["customer_name", "fetched_at"]).unwrap();
+
+        assert_eq!(df.get_column_names(), ["segments.device", "1111111111", "2222222222"]);
+        assert_eq!(df.height(), 2);
+        let clicks: Vec<Option<i64>> = df.column("2222222222").unwrap().i64().unwrap().into_iter().collect();
+        assert_eq!(clicks, [Some(2), Some(4)]);
+    }
+
+    #[test]
+    fn compare_accounts_after_humanize_metrics() {
+        let mut df = df!(
+            "customer_id" => ["1111111111", "2222222222"],
+            "segments.device" => ["MOBILE", "MOBILE"],
+            "metrics.cost_micros" => [1_500_000i64, 2_000_000]
+        )
+        .unwrap();
+        googleads::humanize_metrics(&mut df, &[]).unwrap();
+
+        let metric = compare_metric_column(&df, "metrics.cost_micros", Some(&[]));
+        assert_eq!(metric, "metrics.cost");
+        let df = compare_accounts(&df, "customer_id", &metric, &[]).unwrap();
+        let cost: Vec<Option<f64>> = df.column("1111111111").unwrap().f64().unwrap().into_iter().collect();
+        assert_eq!(cost, [Some(1.5)]);
+
+        // without humanizing, the metric keeps its name
+        let df = df!("metrics.cost_micros" => [1i64]).unwrap();
+        assert_eq!(compare_metric_column(&df, "metrics.cost_micros", None), "metrics.cost_micros");
+    }
+
+    #[test]
+    fn drop_zero_metric_rows_requires_metrics() {
+        let df = df!("campaign.name" => ["a"]).unwrap();
+
+        assert!(drop_zero_metric_rows(df).is_err());
+    }
+
+    #[test]
+    fn rename_columns_partial_map() {
+        let mut df = df!(
+            "campaign.name" => ["a"],
+            "metrics.clicks" => [1u64],
+            "metrics.impressions" => [10u64]
+        )
+        .unwrap();
+        let renames = [
+            ("metrics.clicks".to_owned(), "Clicks".to_owned()),
+            // absent columns are skipped
+            ("metrics.cost_micros".to_owned(), "Cost".to_owned()),
+            // as are renames to names already taken
+            ("metrics.impressions".to_owned(), "campaign.name".to_owned()),
+        ];
+
+        rename_columns(&mut df, &renames).unwrap();
+
+        assert_eq!(df.get_column_names(), ["campaign.name", "Clicks", "metrics.impressions"]);
+    }
+
+    #[test]
+    fn fetched_at_column_is_constant_and_last() {
+        let mut df = df!(
+            "campaign.name" => ["a", "b", "c"],
+            "metrics.clicks" => [1u64, 2, 3]
+        )
+        .unwrap();
+
+        add_fetched_at_column(&mut df, "fetched_at", "2024-01-02T03:04:05Z").unwrap();
+
+        assert_eq!(df.get_column_names(), ["campaign.name", "metrics.clicks", "fetched_at"]);
+        let values: Vec<Option<&str>> = df.column("fetched_at").unwrap().str().unwrap().into_iter().collect();
+        assert_eq!(values, [Some("2024-01-02T03:04:05Z"); 3]);
+    }
+
+    #[test]
+    fn parse_cast_specs() {
+        assert_eq!(parse_cast("campaign.id:str").unwrap(), ("campaign.id".to_owned(), DataType::String));
+        assert_eq!(parse_cast("metrics.clicks:i64").unwrap(), ("metrics.clicks".to_owned(), DataType::Int64));
+        assert_eq!(parse_cast("a:b:f64").unwrap(), ("a:b".to_owned(), DataType::Float64));
+        assert_eq!(parse_cast("flag:bool").unwrap(), ("flag".to_owned(), DataType::Boolean));
+
+        assert!(parse_cast("campaign.id").is_err());
+        assert!(parse_cast(":i64").is_err());
+        assert!(parse_cast("campaign.id:u8").unwrap_err().to_string().contains("unsupported type 'u8'"));
+    }
+
+    #[test]
+    fn apply_casts_converts_types() {
+        let mut df = df!(
+            "campaign.id" => ["123", "456"],
+            "metrics.clicks" => [1i64, 2]
+        )
+        .unwrap();
+        let casts = [
+            ("campaign.id".to_owned(), DataType::Int64),
+            ("metrics.clicks".to_owned(), DataType::String),
+        ];
+
+        apply_casts(&mut df, &casts).unwrap();
+
+        assert_eq!(df.column("campaign.id").unwrap().i64().unwrap().get(1), Some(456));
+        assert_eq!(df.column("metrics.clicks").unwrap().str().unwrap().get(0), Some("1"));
+    }
+
+    #[test]
+    fn apply_casts_fails_on_unconvertible_values() {
+        let mut df = df!("campaign.name" => ["123", "Brand"]).unwrap();
+
+        assert!(apply_casts(&mut df, &[("campaign.name".to_owned(), DataType::Int64)]).is_err());
+        assert!(apply_casts(&mut df, &[("missing".to_owned(), DataType::Int64)]).is_err());
+        assert_eq!(df.column("campaign.name").unwrap().dtype(), &DataType::String);
+    }
+
+    #[test]
+    fn customer_id_column_is_first() {
+        let mut df = df!(
+            "campaign.name" => ["a", "b"],
+            "metrics.clicks" => [1u64, 2]
+        )
+        .unwrap();
+
+        add_customer_id_column(&mut df, "customer_id", "1111111111").unwrap();
+
+        assert_eq!(df.get_column_names(), ["customer_id", "campaign.name", "metrics.clicks"]);
+        let values: Vec<Option<&str>> = df.column("customer_id").unwrap().str().unwrap().into_iter().collect();
+        assert_eq!(values, [Some("1111111111"); 2]);
+    }
+
+    #[test]
+    fn customer_id_column_name_override() {
+        let args = args::Cli::parse_from([
+            "mcc-gaql",
+            "--add-customer-id",
+            "--customer-id-column",
+            "account",
+            "SELECT campaign.id FROM campaign",
+        ]);
+        let mut df = df!("campaign.id" => ["1"]).unwrap();
+
+        add_customer_id_column(&mut df, &args.customer_id_column, "1111111111").unwrap();
+
+        assert_eq!(df.get_column_names(), ["account", "campaign.id"]);
+    }
+
+    #[test]
+    fn quote_style_values() {
+        let csv = |style: &str| {
+            let mut df = df!(
+                "campaign.name" => ["a,b", "123"],
+                "metrics.clicks" => [1u64, 2]
+            )
+            .unwrap();
+            let mut buffer: Vec<u8> = Vec::new();
+            CsvWriter::new(&mut buffer)
+                .with_quote_style(quote_style(style))
+                .finish(&mut df)
+                .unwrap();
+            String::from_utf8(buffer).unwrap()
+        };
+
+        assert_eq!(csv("necessary"), "campaign.name,metrics.clicks\n\"a,b\",1\n123,2\n");
+        assert_eq!(
+            csv("always"),
+            "\"campaign.name\",\"metrics.clicks\"\n\"a,b\",\"1\"\n\"123\",\"2\"\n"
+        );
+        // numeric-looking strings are still quoted
+        assert_eq!(
+            csv("non_numeric"),
+            "\"campaign.name\",\"metrics.clicks\"\n\"a,b\",1\n\"123\",2\n"
+        );
+        // values containing the separator are written as is
+        assert_eq!(csv("never"), "campaign.name,metrics.clicks\na,b,1\n123,2\n");
+        // unknown values fall back to necessary
+        assert_eq!(csv("other"), csv("necessary"));
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{bail, Result};
+use hyper::{Body, Client, Method, Request};
+use polars::prelude::*;
+use serde_json::{json, Value};
+use yup_oauth2::authenticator::{DefaultHyperClient, HyperClientBuilder};
+
+use crate::googleads::GoogleAdsAPIAccess;
+
+static GOOGLE_SHEETS_API_SCOPE: &str = "https://www.googleapis.com/auth/spreadsheets";
+const SHEETS_API_ENDPOINT: &str = "https://sheets.googleapis.com/v4/spreadsheets";
+
+type HttpsClient = Client<<DefaultHyperClient as HyperClientBuilder>::Connector>;
+
+/// Google Sheet range to write GAQL results to
+pub struct SheetTarget {
+    pub spreadsheet_id: String,
+    /// A1 notation range, e.g. `Sheet1` or `Report!A1:Z`
+    pub range: String,
+    /// append rows below existing values instead of replacing the range
+    pub append: bool,
+    /// reuses the Google Ads authenticator, requesting the Sheets scope
+    pub api_context: GoogleAdsAPIAccess,
+}
+
+impl SheetTarget {
+    /// Write DataFrame to the sheet.
+    /// Overwrite mode clears the range and writes a header row; append mode writes data rows only.
+    pub async fn write(&self, df: &DataFrame) -> Result<()> {
+        let token = match self
+            .api_context
+            .authenticator
+            .token(&[GOOGLE_SHEETS_API_SCOPE])
+            .await
+        {
+            Ok(t) => t,
+            Err(e) => bail!("failed to get Sheets access token: {:?}", e),
+        };
+        let bearer_token = format!("Bearer {}", token.as_str());
+
+        let client: HttpsClient = DefaultHyperClient.build_hyper_client();
+        let base = format!(
+            "{SHEETS_API_ENDPOINT}/{}/values/{}",
+            self.spreadsheet_id,
+            encode_range(&self.range)
+        );
+        let values = json!({ "values": dataframe_values(df, !self.append) });
+
+        if self.append {
+            let uri = format!("{base}:append?valueInputOption=RAW&insertDataOption=INSERT_ROWS");
+            send(&client, Method::POST, &uri, &bearer_token, values).await?;
+        } else {
+            send(&client, Method::POST, &format!("{base}:clear"), &bearer_token, json!({})).await?;
+            let uri = format!("{base}?valueInputOption=RAW");
+            send(&client, Method::PUT, &uri, &bearer_token, values).await?;
+        }
+
+        log::info!(
+            "{} {} rows to sheet {} range {}",
+            if self.append { "Appended" } else { "Wrote" },
+            df.height(),
+            self.spreadsheet_id,
+            self.range
+        );
+
+        Ok(())
+    }
+}
+
+async fn send(
+    client: &HttpsClient,
+    method: Method,
+    uri: &str,
+    bearer_token: &str,
+    body: Value,
+) -> Result<()> {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("authorization", bearer_token)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))?;
+
+    let response = client.request(request).await?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await?;
+        bail!(
+            "Sheets API request failed with {status}: {}",
+            String::from_utf8_lossy(&bytes)
+        );
+    }
+
+    Ok(())
+}
+
+/// percent-encode A1 range for use as a URL path segment
+fn encode_range(range: &str) -> String {
+    range
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'!' | b':' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// convert DataFrame into rows of JSON cell values
+fn dataframe_values(df: &DataFrame, include_header: bool) -> Vec<Vec<Value>> {
+    let mut rows = Vec::with_capacity(df.height() + 1);
+
+    if include_header {
+        rows.push(
+            df.get_column_names()
+                .iter()
+                .map(|name| Value::from(name.to_string()))
+                .collect(),
+        );
+    }
+
+    let columns = df.get_columns();
+    for i in 0..df.height() {
+        rows.push(
+            columns
+                .iter()
+                .map(|s| s.get(i).map(cell_value).unwrap_or(Value::Null))
+                .collect(),
+        );
+    }
+
+    rows
+}
+
+fn cell_value(value: AnyValue) -> Value {
+    match value {
+        AnyValue::Null => Value::from(""),
+        AnyValue::Boolean(b) => Value::from(b),
+        AnyValue::String(s) => Value::from(s),
+        AnyValue::Int32(n) => Value::from(n),
+        AnyValue::Int64(n) => Value::from(n),
+        AnyValue::UInt32(n) => Value::from(n),
+        AnyValue::UInt64(n) => Value::from(n),
+        AnyValue::Float32(n) => Value::from(n),
+        AnyValue::Float64(n) => Value::from(n),
+        other => Value::from(other.to_string()),
+    }
+}
@@ -1,11 +1,21 @@
 use anyhow::{bail, Result};
+use chrono::{Days, Months, NaiveDate};
+use encoding_rs::{EncoderResult, Encoding};
 use std::{
-    env,
-    fs::File,
-    io::{BufRead, BufReader, Read},
-    path::Path,
+    collections::{HashMap, HashSet},
+    env, fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    process,
 };
-use toml::Value;
+use toml::{value::Table, Value};
+
+/// date format used by GAQL date literals
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// `--accounts` value to read CustomerIDs from stdin
+pub const ACCOUNTS_FROM_STDIN: &str = "@-";
 
 #[allow(dead_code)]
 const CACHE_FILENAME: &str = ".cache";
@@ -15,6 +25,7 @@ const CACHE_KEY_CHILD_ACCOUNTS: &str = "child-accounts";
 /// initialize Flexi Logger via Env Vars
 /// <prefix>_LOG_LEVEL sets logging level
 /// <prefix>_LOG_DIR sets log file path
+/// <prefix>_LOG_FORMAT=json writes one JSON object per line instead of text
 pub fn init_logger() {
     use flexi_logger::{Cleanup, Criterion, Duplicate, FileSpec, Logger, Naming};
 
@@ -22,6 +33,14 @@ pub fn init_logger() {
         .unwrap_or_else(|_| "off".to_string());
     let my_log_dir = env::var(format!("{}{}", crate::config::ENV_VAR_PREFIX, "LOG_DIR"))
         .unwrap_or_else(|_| ".".to_string());
+    let my_log_format = env::var(format!("{}{}", crate::config::ENV_VAR_PREFIX, "LOG_FORMAT"))
+        .unwrap_or_else(|_| "text".to_string());
+
+    let format: flexi_logger::FormatFunction = if my_log_format.eq_ignore_ascii_case("json") {
+        json_format
+    } else {
+        flexi_logger::detailed_format
+    };
 
     Logger::try_with_env_or_str(my_log_env)
         .unwrap()
@@ -31,7 +50,7 @@ pub fn init_logger() {
                 .directory(my_log_dir)
                 .suppress_timestamp(),
         )
-        .format_for_files(flexi_logger::detailed_format)
+        .format_for_files(format)
         .o_append(true)
         .rotate(
             Criterion::Size(1_000_000),
@@ -43,6 +62,145 @@ pub fn init_logger() {
         .unwrap();
 }
 
+/// Log an error and exit. Printed to stderr, so it doesn't end up in piped output
+pub fn fail(msg: impl fmt::Display) -> ! {
+    log::error!("{msg}");
+    eprintln!("{msg}");
+    process::exit(1);
+}
+
+/// Log line as a JSON object with level, timestamp, module and message,
+/// plus any structured fields such as customer_id and run_id
+fn json_format(
+    w: &mut dyn io::Write,
+    now: &mut flexi_logger::DeferredNow,
+    record: &log::Record,
+) -> io::Result<()> {
+    struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            self.0.insert(key.to_string(), value.to_string().into());
+            Ok(())
+        }
+    }
+
+    let timestamp = chrono::DateTime::from_timestamp_nanos(now.now().unix_timestamp_nanos() as i64);
+
+    let mut line = serde_json::Map::new();
+    line.insert("level".to_owned(), record.level().as_str().into());
+    line.insert(
+        "timestamp".to_owned(),
+        timestamp.to_rfc3339_opts(chrono::SecondsFormat::Micros, true).into(),
+    );
+    line.insert("module".to_owned(), record.module_path().unwrap_or("<unnamed>").into());
+    line.insert("message".to_owned(), record.args().to_string().into());
+    record
+        .key_values()
+        .visit(&mut Fields(&mut line))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+
+    write!(w, "{}", serde_json::Value::Object(line))
+}
+
+/// get per-account queries from a TOML file of `"<customer_id>" = "<query>"` entries
+pub fn get_query_map_from_file<P>(filename: P) -> Result<HashMap<String, String>>
+where
+    P: AsRef<Path>,
+{
+    let contents = match fs::read_to_string(&filename) {
+        Ok(s) => s,
+        Err(e) => bail!("Unable to load query map from file: {e}"),
+    };
+    let table: Table = match toml::from_str(&contents) {
+        Ok(table) => table,
+        Err(e) => bail!("Invalid query map TOML: {e}"),
+    };
+
+    let mut queries: HashMap<String, String> = HashMap::new();
+    let mut invalid_ids: Vec<String> = Vec::new();
+
+    for (id, query) in table {
+        let Value::String(query) = query else {
+            bail!("Invalid query map entry for '{id}', expected a query string");
+        };
+        let customer_id = id.trim().replace('-', "");
+        if !is_valid_customer_id(&customer_id) {
+            invalid_ids.push(id);
+            continue;
+        }
+        queries.insert(customer_id, query);
+    }
+
+    if !invalid_ids.is_empty() {
+        bail!("Invalid CustomerIDs in query map file: {}", invalid_ids.join(", "));
+    }
+
+    log::debug!(
+        "Loaded {} account queries from file {}",
+        queries.len(),
+        filename.as_ref().display()
+    );
+
+    Ok(queries)
+}
+
+/// get column renames from a CSV file of from,to lines, or a `.toml` file of `"from" = "to"` entries
+pub fn get_rename_map_from_file<P>(filename: P) -> Result<Vec<(String, String)>>
+where
+    P: AsRef<Path>,
+{
+    let path = filename.as_ref();
+    let contents = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => bail!("Unable to load rename map from file: {e}"),
+    };
+
+    let is_toml = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+
+    let mut renames: Vec<(String, String)> = Vec::new();
+
+    if is_toml {
+        let table: Table = match toml::from_str(&contents) {
+            Ok(table) => table,
+            Err(e) => bail!("Invalid rename map TOML: {e}"),
+        };
+        for (from, to) in table {
+            let Value::String(to) = to else {
+                bail!("Invalid rename map entry for '{from}', expected a string label");
+            };
+            renames.push((from, to));
+        }
+    } else {
+        for (i, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some((from, to)) = line.split_once(',') else {
+                bail!("Invalid rename map entry on line {}, expected from,to: {line}", i + 1);
+            };
+            let from = from.trim().trim_matches('"');
+            let to = to.trim().trim_matches('"');
+
+            // header line
+            if i == 0 && from.eq_ignore_ascii_case("from") && to.eq_ignore_ascii_case("to") {
+                continue;
+            }
+            renames.push((from.to_owned(), to.to_owned()));
+        }
+    }
+
+    log::debug!("Loaded {} column renames from file {}", renames.len(), path.display());
+
+    Ok(renames)
+}
+
 /// fetch child account ids from cache
 pub async fn _get_child_accounts_from_cache() -> Option<Vec<String>> {
     match cacache::read(CACHE_FILENAME, CACHE_KEY_CHILD_ACCOUNTS).await {
@@ -115,6 +273,715 @@ where
     }
 }
 
+/// get account labels from a local `id,name` CSV file, keyed by CustomerID.
+/// Dashes in IDs are ignored, and a header line is skipped.
+pub fn get_account_labels_from_file<P>(filename: P) -> Result<HashMap<String, String>>
+where
+    P: AsRef<Path>,
+{
+    let contents = match fs::read_to_string(&filename) {
+        Ok(s) => s,
+        Err(e) => bail!("Unable to load account labels from file: {e}"),
+    };
+
+    let mut labels: HashMap<String, String> = HashMap::new();
+    let mut invalid_ids: Vec<String> = Vec::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((id, name)) = line.split_once(',') else {
+            bail!("Invalid account label on line {}, expected id,name: {line}", i + 1);
+        };
+        let customer_id = id.trim().trim_matches('"').replace('-', "");
+
+        if !is_valid_customer_id(&customer_id) {
+            // header line
+            if i == 0 {
+                continue;
+            }
+            invalid_ids.push(id.trim().to_owned());
+            continue;
+        }
+
+        labels.insert(customer_id, name.trim().trim_matches('"').to_owned());
+    }
+
+    if !invalid_ids.is_empty() {
+        bail!("Invalid CustomerIDs in account labels file: {}", invalid_ids.join(", "));
+    }
+
+    log::debug!(
+        "Loaded {} account labels from file {}",
+        labels.len(),
+        filename.as_ref().display()
+    );
+
+    Ok(labels)
+}
+
+/// get `(mcc_id, customer_id)` pairs from a CSV file, for accounts under different manager accounts.
+/// Dashes in IDs are ignored, and a header line is skipped.
+pub fn get_account_pairs_from_file<P>(filename: P) -> Result<Vec<(String, String)>>
+where
+    P: AsRef<Path>,
+{
+    let contents = match fs::read_to_string(&filename) {
+        Ok(s) => s,
+        Err(e) => bail!("Unable to load account pairs from file: {e}"),
+    };
+
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    let mut login_customers: HashMap<String, String> = HashMap::new();
+
+    for (i, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some((mcc_id, customer_id)) = line.split_once(',') else {
+            bail!("Invalid account pair on line {}, expected mcc_id,customer_id: {line}", i + 1);
+        };
+        let mcc_id = mcc_id.trim().replace('-', "");
+        let customer_id = customer_id.trim().replace('-', "");
+
+        if !is_valid_customer_id(&mcc_id) || !is_valid_customer_id(&customer_id) {
+            // header line
+            if i == 0 {
+                continue;
+            }
+            bail!("Invalid CustomerID in account pair on line {}: {line}", i + 1);
+        }
+
+        match login_customers.get(&customer_id) {
+            Some(existing) if *existing != mcc_id => {
+                bail!("CustomerID {customer_id} is paired with both MCC {existing} and {mcc_id}");
+            }
+            Some(_) => continue,
+            None => {
+                login_customers.insert(customer_id.clone(), mcc_id.clone());
+                pairs.push((mcc_id, customer_id));
+            }
+        }
+    }
+
+    log::debug!(
+        "Loaded {} account pairs from file {}",
+        pairs.len(),
+        filename.as_ref().display()
+    );
+
+    Ok(pairs)
+}
+
+/// check that CustomerID is 10 digits without dashes, as expected by the API
+pub fn is_valid_customer_id(customer_id: &str) -> bool {
+    customer_id.len() == 10 && customer_id.chars().all(|c| c.is_ascii_digit())
+}
+
+/// get account ids from `--accounts` spec: comma-separated CustomerIDs, a file path, or `@-` for stdin
+pub async fn get_account_ids_from_spec(spec: &str) -> Result<Vec<String>> {
+    let spec = spec.trim();
+
+    if spec == ACCOUNTS_FROM_STDIN {
+        let mut customer_ids: Vec<String> = Vec::with_capacity(2048);
+        let mut invalid_ids: Vec<String> = Vec::new();
+
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            let customer_id = line.trim();
+            if customer_id.is_empty() {
+                continue;
+            }
+            if is_valid_customer_id(customer_id) {
+                customer_ids.push(customer_id.to_owned());
+            } else {
+                invalid_ids.push(customer_id.to_owned());
+            }
+        }
+
+        if !invalid_ids.is_empty() {
+            bail!("Invalid CustomerIDs read from stdin: {}", invalid_ids.join(", "));
+        }
+
+        log::debug!("Loaded {} customer_ids from stdin", customer_ids.len());
+
+        return Ok(customer_ids);
+    }
+
+    let inline_ids: Vec<&str> = spec.split(',').map(str::trim).collect();
+    let is_inline = inline_ids.iter().all(|id| is_valid_customer_id(id));
+    let is_file = Path::new(spec).is_file();
+
+    match (is_inline, is_file) {
+        (true, true) => {
+            bail!("Ambiguous --accounts value '{spec}': it is both a CustomerID list and an existing file");
+        }
+        (true, false) => Ok(inline_ids.into_iter().map(str::to_owned).collect()),
+        (false, true) => get_child_account_ids_from_file(spec).await,
+        (false, false) => {
+            if inline_ids.len() > 1 {
+                let invalid_ids: Vec<&str> = inline_ids
+                    .into_iter()
+                    .filter(|id| !is_valid_customer_id(id))
+                    .collect();
+                bail!(
+                    "Invalid CustomerIDs in --accounts list: {}",
+                    invalid_ids.join(", ")
+                );
+            }
+            bail!(
+                "Invalid --accounts value '{spec}': expected 10-digit CustomerIDs separated by commas, an existing file, or '{ACCOUNTS_FROM_STDIN}'"
+            );
+        }
+    }
+}
+
+/// find byte offset of a GAQL keyword (case-insensitive, whitespace delimited) at or after `from`,
+/// skipping string literals
+pub fn find_keyword(query: &str, keyword: &str, from: usize) -> Option<usize> {
+    let upper = query.to_ascii_uppercase();
+    let bytes = upper.as_bytes();
+    let literals = string_literal_spans(query);
+    let mut start = from;
+
+    while let Some(i) = upper.get(start..)?.find(keyword) {
+        let pos = start + i;
+        let end = pos + keyword.len();
+        let is_word_start = pos == 0 || bytes[pos - 1].is_ascii_whitespace();
+        let is_word_end = end == bytes.len() || bytes[end].is_ascii_whitespace();
+        let is_quoted = literals.iter().any(|(open, close)| (*open..*close).contains(&pos));
+        if is_word_start && is_word_end && !is_quoted {
+            return Some(pos);
+        }
+        start = pos + 1;
+    }
+
+    None
+}
+
+/// byte ranges of single- or double-quoted string literals in a GAQL query, honoring backslash escapes.
+/// An unterminated literal runs to the end of the query.
+fn string_literal_spans(query: &str) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    let mut open: Option<(u8, usize)> = None;
+    let mut escaped = false;
+
+    for (i, &b) in query.as_bytes().iter().enumerate() {
+        match open {
+            Some(_) if escaped => escaped = false,
+            Some(_) if b == b'\\' => escaped = true,
+            Some((quote, start)) if b == quote => {
+                spans.push((start, i + 1));
+                open = None;
+            }
+            Some(_) => {}
+            None if b == b'\'' || b == b'"' => open = Some((b, i)),
+            None => {}
+        }
+    }
+    if let Some((_, start)) = open {
+        spans.push((start, query.len()));
+    }
+
+    spans
+}
+
+/// extract field names listed in the SELECT clause of a GAQL query
+pub fn get_select_fields(query: &str) -> Vec<String> {
+    let Some(select_pos) = find_keyword(query, "SELECT", 0) else {
+        return Vec::new();
+    };
+    let fields_start = select_pos + "SELECT".len();
+    let fields_end = find_keyword(query, "FROM", fields_start).unwrap_or(query.len());
+
+    query[fields_start..fields_end]
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// add a condition to the WHERE clause of a GAQL query, creating the clause if needed
+pub fn add_where_condition(query: &str, condition: &str) -> String {
+    let from_pos = find_keyword(query, "FROM", 0).unwrap_or(0);
+
+    // WHERE clause ends at the first trailing clause, if any
+    let tail_pos = ["ORDER", "LIMIT", "PARAMETERS"]
+        .iter()
+        .filter_map(|keyword| find_keyword(query, keyword, from_pos))
+        .min()
+        .unwrap_or(query.len());
+
+    let (head, tail) = query.split_at(tail_pos);
+    let head = head.trim_end();
+
+    let clause = if find_keyword(head, "WHERE", from_pos).is_some() {
+        "AND"
+    } else {
+        "WHERE"
+    };
+
+    format!("{head} {clause} {condition} {tail}")
+        .trim_end()
+        .to_string()
+}
+
+/// rewrite a GAQL query to select only `field`, dropping ORDER BY since it may sort on fields no longer selected
+pub fn single_field_query(query: &str, field: &str) -> Option<String> {
+    let select_pos = find_keyword(query, "SELECT", 0)?;
+    let from_pos = find_keyword(query, "FROM", select_pos)?;
+    let mut rest = query[from_pos..].to_owned();
+
+    if let Some(order_pos) = find_keyword(&rest, "ORDER", 0) {
+        let order_end = ["LIMIT", "PARAMETERS"]
+            .iter()
+            .filter_map(|keyword| find_keyword(&rest, keyword, order_pos))
+            .min()
+            .unwrap_or(rest.len());
+        rest = format!("{} {}", rest[..order_pos].trim_end(), &rest[order_end..]);
+    }
+
+    Some(format!("SELECT {field} {}", rest.trim_end()))
+}
+
+/// add a LIMIT clause to a GAQL query, unless it already has one
+pub fn add_limit(query: &str, limit: usize) -> String {
+    if find_keyword(query, "LIMIT", 0).is_some() {
+        return query.to_owned();
+    }
+
+    match find_keyword(query, "PARAMETERS", 0) {
+        Some(pos) => {
+            let (head, tail) = query.split_at(pos);
+            format!("{} LIMIT {limit} {tail}", head.trim_end())
+        }
+        None => format!("{} LIMIT {limit}", query.trim_end()),
+    }
+}
+
+/// set a PARAMETERS option on a GAQL query, unless the query already sets it.
+/// Existing options are kept, and the clause is placed last, after any ORDER BY and LIMIT
+pub fn add_parameter(query: &str, name: &str, value: &str) -> String {
+    let Some(pos) = find_keyword(query, "PARAMETERS", 0) else {
+        return format!("{} PARAMETERS {name} = {value}", query.trim_end());
+    };
+
+    // clause runs to the end, or to a misplaced ORDER BY or LIMIT following it
+    let clause_start = pos + "PARAMETERS".len();
+    let clause_end = ["ORDER", "LIMIT"]
+        .iter()
+        .filter_map(|keyword| find_keyword(query, keyword, clause_start))
+        .min()
+        .unwrap_or(query.len());
+
+    let mut options: Vec<&str> = query[clause_start..clause_end]
+        .split(',')
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+        .collect();
+    let is_set = options.iter().any(|option| {
+        option
+            .split('=')
+            .next()
+            .is_some_and(|key| key.trim().eq_ignore_ascii_case(name))
+    });
+    let added = format!("{name} = {value}");
+    if !is_set {
+        options.push(&added);
+    }
+
+    let rest = format!("{} {}", query[..pos].trim_end(), query[clause_end..].trim());
+    format!("{} PARAMETERS {}", rest.trim_end(), options.join(", "))
+}
+
+/// stable 64-bit FNV-1a hash, for keys that must not change across builds or runs
+pub fn stable_hash(data: &str) -> u64 {
+    data.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// stable fingerprint of the queries run against a set of accounts, after params, date windows and other rewrites.
+/// Account order does not matter; accounts with their own query from `query_map` contribute that query instead.
+pub fn query_fingerprint(
+    queries: &[String],
+    customer_ids: &[String],
+    query_map: &HashMap<String, String>,
+) -> String {
+    let mut customer_ids: Vec<&String> = customer_ids.iter().collect();
+    customer_ids.sort();
+    customer_ids.dedup();
+
+    let default_query = queries.join("\n");
+    let data: String = customer_ids
+        .iter()
+        .map(|customer_id| {
+            let query = query_map.get(*customer_id).unwrap_or(&default_query);
+            format!("{customer_id}\t{query}\n")
+        })
+        .collect();
+
+    format!("{:016x}", stable_hash(&data))
+}
+
+/// state file recording the last successful run date for a profile and query
+fn last_run_state_path(profile: &str, query: &str) -> Option<PathBuf> {
+    let key = stable_hash(&format!("{profile}\n{query}"));
+    crate::config::cache_file_path(&format!("last-run-{key:016x}.txt"))
+}
+
+/// get the date of the last successful run for a profile and query, if recorded
+pub fn read_last_run_date(profile: &str, query: &str) -> Result<Option<NaiveDate>> {
+    let Some(path) = last_run_state_path(profile, query) else {
+        bail!("Unable to locate cache dir for last run state");
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(s) => match NaiveDate::parse_from_str(s.trim(), DATE_FORMAT) {
+            Ok(date) => Ok(Some(date)),
+            Err(e) => bail!("Invalid last run state in {}: {e}", path.display()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => bail!("Unable to read last run state {}: {e}", path.display()),
+    }
+}
+
+/// record the date of a successful run for a profile and query
+pub fn write_last_run_date(profile: &str, query: &str, date: NaiveDate) -> Result<()> {
+    let Some(path) = last_run_state_path(profile, query) else {
+        bail!("Unable to locate cache dir for last run state");
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, date.format(DATE_FORMAT).to_string())?;
+
+    log::debug!("Recorded last run date {date} in {}", path.display());
+
+    Ok(())
+}
+
+/// state file recording per-account row counts from runs of a query
+fn account_sizes_path(query: &str) -> Option<PathBuf> {
+    let key = stable_hash(query);
+    crate::config::cache_file_path(&format!("account-sizes-{key:016x}.json"))
+}
+
+/// get row counts by customer_id recorded for previous runs of a query, if any
+pub fn read_account_sizes(query: &str) -> Result<HashMap<String, usize>> {
+    let Some(path) = account_sizes_path(query) else {
+        bail!("Unable to locate cache dir for account sizes");
+    };
+
+    match fs::read_to_string(&path) {
+        Ok(s) => match serde_json::from_str(&s) {
+            Ok(sizes) => Ok(sizes),
+            Err(e) => bail!("Invalid account sizes in {}: {e}", path.display()),
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => bail!("Unable to read account sizes {}: {e}", path.display()),
+    }
+}
+
+/// record row counts by customer_id for a run of a query, keeping counts of accounts not in this run
+pub fn write_account_sizes(query: &str, sizes: &HashMap<String, usize>) -> Result<()> {
+    let Some(path) = account_sizes_path(query) else {
+        bail!("Unable to locate cache dir for account sizes");
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // unreadable history is replaced
+    let mut merged = read_account_sizes(query).unwrap_or_default();
+    merged.extend(sizes.iter().map(|(id, rows)| (id.clone(), *rows)));
+    fs::write(&path, serde_json::to_string(&merged)?)?;
+
+    log::debug!("Recorded row counts of {} accounts in {}", sizes.len(), path.display());
+
+    Ok(())
+}
+
+/// parse a YYYY-MM-DD date
+pub fn parse_date(s: &str) -> Result<NaiveDate> {
+    match NaiveDate::parse_from_str(s.trim(), DATE_FORMAT) {
+        Ok(date) => Ok(date),
+        Err(e) => bail!("Invalid date '{s}', expected YYYY-MM-DD: {e}"),
+    }
+}
+
+/// parse an absolute YYYY-MM-DD date, `today`, `yesterday`, or a relative date resolved against today:
+/// a count of days, weeks, months or years ago, e.g. `30d`, `2w`, `3m`, `1y` (a leading `-` is optional)
+pub fn parse_relative_date(s: &str, today: NaiveDate) -> Result<NaiveDate> {
+    let s = s.trim();
+
+    match s.to_ascii_lowercase().as_str() {
+        "today" => return Ok(today),
+        "yesterday" => return Ok(today - Days::new(1)),
+        _ => {}
+    }
+
+    let relative = s.strip_prefix('-').unwrap_or(s);
+    let Some(unit) = relative.chars().last().filter(char::is_ascii_alphabetic) else {
+        return parse_date(s);
+    };
+    let Ok(n) = relative[..relative.len() - 1].parse::<u32>() else {
+        bail!("Invalid date '{s}', expected YYYY-MM-DD, today, yesterday, or a relative date like 30d, 2w, 3m, 1y");
+    };
+
+    let date = match unit.to_ascii_lowercase() {
+        'd' => today.checked_sub_days(Days::new(n.into())),
+        'w' => today.checked_sub_days(Days::new(u64::from(n) * 7)),
+        'm' => today.checked_sub_months(Months::new(n)),
+        'y' => n.checked_mul(12).and_then(|months| today.checked_sub_months(Months::new(months))),
+        _ => bail!("Invalid date '{s}': unknown unit '{unit}', expected d, w, m or y"),
+    };
+
+    match date {
+        Some(date) => Ok(date),
+        None => bail!("Invalid date '{s}': out of range"),
+    }
+}
+
+/// encode UTF-8 text in another encoding, writing `placeholder` bytes in place of unmappable characters.
+/// Returns the encoded bytes and the number of characters replaced.
+pub fn transcode(text: &str, encoding: &'static Encoding, placeholder: &[u8]) -> (Vec<u8>, usize) {
+    let mut encoder = encoding.new_encoder();
+    let mut encoded: Vec<u8> = Vec::with_capacity(text.len());
+    let mut buffer = [0u8; 8192];
+    let mut rest = text;
+    let mut replaced = 0;
+
+    loop {
+        let (result, read, written) =
+            encoder.encode_from_utf8_without_replacement(rest, &mut buffer, true);
+        encoded.extend_from_slice(&buffer[..written]);
+        rest = &rest[read..];
+
+        match result {
+            EncoderResult::InputEmpty => break,
+            EncoderResult::OutputFull => {}
+            EncoderResult::Unmappable(_) => {
+                encoded.extend_from_slice(placeholder);
+                replaced += 1;
+            }
+        }
+    }
+
+    (encoded, replaced)
+}
+
+/// make a string safe to use as part of a filename
+pub fn sanitize_filename(s: &str) -> String {
+    let sanitized: String = s
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let sanitized = sanitized.trim_start_matches('.');
+
+    if sanitized.is_empty() {
+        "empty".to_owned()
+    } else {
+        sanitized.to_owned()
+    }
+}
+
+/// GAQL operators that take a parenthesized list of values
+const LIST_OPERATORS: &[&str] = &["IN", "CONTAINS ANY", "CONTAINS ALL", "CONTAINS NONE"];
+
+/// substitute `{{name}}` placeholders in query with values from `name=value` params.
+/// Inside a list clause, e.g. `IN ({{name}})`, comma-separated values expand to a quoted list;
+/// elsewhere the value is inserted as-is with quotes escaped.
+pub fn apply_query_params(query: &str, params: &[String]) -> Result<String> {
+    substitute_query_params(query, params, None)
+}
+
+/// substitute placeholders like `apply_query_params`, but leave `{{deferred}}` in place,
+/// e.g. to fill in a different chunk of an IN-list per query run
+pub fn apply_query_params_deferring(query: &str, params: &[String], deferred: &str) -> Result<String> {
+    substitute_query_params(query, params, Some(deferred))
+}
+
+fn substitute_query_params(query: &str, params: &[String], deferred: Option<&str>) -> Result<String> {
+    let mut values: HashMap<&str, &str> = HashMap::new();
+    for param in params {
+        let Some((name, value)) = param.split_once('=') else {
+            bail!("Invalid --param '{param}': expected name=value");
+        };
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("Invalid --param name '{name}': use only letters, digits and underscores");
+        }
+        values.insert(name, value);
+    }
+
+    let mut substituted = String::with_capacity(query.len());
+    let mut used: HashSet<&str> = HashSet::new();
+    let mut rest = query;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            bail!("Unterminated placeholder in query: {}", &rest[start..]);
+        };
+        let name = rest[start + 2..start + 2 + len].trim();
+        if deferred == Some(name) {
+            substituted.push_str(&rest[..start + 2 + len + 2]);
+            rest = &rest[start + 2 + len + 2..];
+            continue;
+        }
+        let Some((name, value)) = values.get_key_value(name) else {
+            bail!("Missing --param for query placeholder: {name}");
+        };
+        used.insert(name);
+
+        substituted.push_str(&rest[..start]);
+        if is_list_context(&substituted) {
+            substituted.push_str(&format_value_list(name, value)?);
+        } else {
+            substituted.push_str(&escape_quotes(value));
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    substituted.push_str(rest);
+
+    for name in values.keys().filter(|name| !used.contains(*name)) {
+        log::warn!("--param '{name}' not used by query");
+    }
+
+    Ok(substituted)
+}
+
+/// remove repeated CustomerIDs, keeping the first occurrence of each in order.
+/// Returns the removed duplicates
+pub fn dedup_account_ids(customer_ids: &mut Vec<String>) -> Vec<String> {
+    let mut seen: HashSet<String> = HashSet::with_capacity(customer_ids.len());
+    let mut duplicates: Vec<String> = Vec::new();
+    customer_ids.retain(|customer_id| {
+        if seen.insert(customer_id.clone()) {
+            true
+        } else {
+            duplicates.push(customer_id.clone());
+            false
+        }
+    });
+    duplicates
+}
+
+/// split a comma-separated `--param` list into comma-separated chunks of at most `size` distinct values
+pub fn chunk_param_values(value: &str, size: usize) -> Vec<String> {
+    let mut seen: HashSet<&str> = HashSet::new();
+    let items: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty() && seen.insert(item))
+        .collect();
+
+    items.chunks(size.max(1)).map(|chunk| chunk.join(",")).collect()
+}
+
+/// check if text preceding a placeholder opens a list clause, e.g. `campaign.status IN (`
+fn is_list_context(preceding: &str) -> bool {
+    let Some(before_paren) = preceding.trim_end().strip_suffix('(') else {
+        return false;
+    };
+    let upper = before_paren.trim_end().to_ascii_uppercase();
+
+    LIST_OPERATORS.iter().any(|op| {
+        upper
+            .strip_suffix(op)
+            .is_some_and(|s| s.ends_with(char::is_whitespace))
+    })
+}
+
+/// format comma-separated values as GAQL list items; numbers are left unquoted
+fn format_value_list(name: &str, value: &str) -> Result<String> {
+    let items: Vec<&str> = value.split(',').map(str::trim).collect();
+    if items.iter().any(|item| item.is_empty()) {
+        bail!("Invalid --param '{name}': empty value in list '{value}'");
+    }
+
+    if items.iter().all(|item| item.parse::<i64>().is_ok()) {
+        Ok(items.join(", "))
+    } else {
+        Ok(items
+            .iter()
+            .map(|item| format!("'{}'", escape_quotes(item)))
+            .collect::<Vec<_>>()
+            .join(", "))
+    }
+}
+
+/// escape backslashes and quotes for use inside a GAQL string literal
+fn escape_quotes(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\'', "\\'")
+        .replace('"', "\\\"")
+}
+
+/// Parse stored query TOML. If the file as a whole is invalid, parse it entry by entry
+/// so one malformed entry doesn't break the rest, logging and returning the entries skipped.
+fn parse_query_entries(buffer: &str) -> (Table, Vec<(String, String)>) {
+    if let Ok(Value::Table(table)) = buffer.parse::<Value>() {
+        return (table, Vec::new());
+    }
+
+    let mut table = Table::new();
+    let mut malformed = Vec::new();
+    for (name, entry) in split_query_entries(buffer) {
+        match entry.parse::<Value>() {
+            Ok(Value::Table(t)) => table.extend(t),
+            Ok(_) => {}
+            Err(e) => {
+                log::warn!("Skipping malformed stored query '{name}': {e}");
+                malformed.push((name, e.to_string()));
+            }
+        }
+    }
+
+    (table, malformed)
+}
+
+/// Split stored query TOML into `(name, text)` entries, each starting at a top-level `name =` line
+fn split_query_entries(buffer: &str) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    let mut in_multiline_string = false;
+
+    for line in buffer.lines() {
+        let starts_entry = !in_multiline_string
+            && line
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '"')
+            && line.contains('=');
+
+        if starts_entry {
+            let name = line.split('=').next().unwrap_or_default().trim().trim_matches('"');
+            entries.push((name.to_owned(), String::new()));
+        }
+
+        // comments and blank lines before the first entry are dropped
+        if let Some((_, entry)) = entries.last_mut() {
+            entry.push_str(line);
+            entry.push('\n');
+        }
+
+        if line.matches("\"\"\"").count() % 2 == 1 {
+            in_multiline_string = !in_multiline_string;
+        }
+    }
+
+    entries
+}
+
 /// get named query from file
 pub async fn get_query_from_file<P>(filename: P, query_name: &str) -> Result<String>
 where
@@ -126,16 +993,8 @@ where
 
             BufReader::new(&file).read_to_string(&mut buffer)?;
 
-            // parse Toml
-            let toml = match buffer.parse::<Value>() {
-                Ok(v) => v,
-                Err(e) => {
-                    bail!(
-                        "Unable to parse stored query toml. Error: {}",
-                        e.to_string()
-                    );
-                }
-            };
+            // parse Toml, skipping malformed entries
+            let (toml, malformed) = parse_query_entries(&buffer);
 
             let query = match toml.get(query_name) {
                 Some(v) => match v.as_str() {
@@ -144,9 +1003,14 @@ where
                         bail!("Query not valid string: {}", v);
                     }
                 },
-                _ => {
-                    bail!("Query not found: {query_name}");
-                }
+                _ => match malformed.iter().find(|(name, _)| name == query_name) {
+                    Some((_, e)) => {
+                        bail!("Unable to parse stored query '{query_name}'. Error: {e}");
+                    }
+                    None => {
+                        bail!("Query not found: {query_name}");
+                    }
+                },
             };
 
             log::debug!(
@@ -162,3 +1026,297 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OMIT: &str = "omit_unselected_resource_names";
+
+    #[test]
+    fn add_parameter_after_limit() {
+        assert_eq!(
+            add_parameter("SELECT campaign.id FROM campaign LIMIT 10", OMIT, "true"),
+            "SELECT campaign.id FROM campaign LIMIT 10 PARAMETERS omit_unselected_resource_names = true"
+        );
+    }
+
+    #[test]
+    fn add_parameter_to_existing_clause() {
+        assert_eq!(
+            add_parameter("SELECT campaign.id FROM campaign PARAMETERS include_drafts = true", OMIT, "true"),
+            "SELECT campaign.id FROM campaign PARAMETERS include_drafts = true, omit_unselected_resource_names = true"
+        );
+        // a misplaced LIMIT is moved before the clause
+        assert_eq!(
+            add_parameter("SELECT campaign.id FROM campaign PARAMETERS include_drafts = true LIMIT 5", OMIT, "true"),
+            "SELECT campaign.id FROM campaign LIMIT 5 PARAMETERS include_drafts = true, omit_unselected_resource_names = true"
+        );
+    }
+
+    #[test]
+    fn add_parameter_already_set() {
+        let query = "SELECT campaign.id FROM campaign PARAMETERS omit_unselected_resource_names = false";
+        assert_eq!(add_parameter(query, OMIT, "true"), query);
+    }
+
+    #[test]
+    fn add_parameter_ignores_keywords_in_literals() {
+        assert_eq!(
+            add_parameter("SELECT campaign.id FROM campaign WHERE campaign.name = 'X PARAMETERS Y LIMIT 1'", OMIT, "true"),
+            "SELECT campaign.id FROM campaign WHERE campaign.name = 'X PARAMETERS Y LIMIT 1' PARAMETERS omit_unselected_resource_names = true"
+        );
+        assert_eq!(find_keyword(r#"SELECT a FROM b WHERE c = "it\"s ORDER" ORDER BY a"#, "ORDER", 0), Some(40));
+    }
+
+    const MIXED_QUERIES: &str = r#"# shared cookbook
+campaigns = "SELECT campaign.id FROM campaign"
+broken = "SELECT ad_group.id FROM ad_group
+keywords = """
+SELECT ad_group_criterion.keyword.text
+FROM keyword_view
+"""
+"#;
+
+    #[test]
+    fn parse_query_entries_keeps_valid_entries() {
+        let (table, malformed) = parse_query_entries(MIXED_QUERIES);
+
+        assert_eq!(
+            table.get("campaigns").and_then(Value::as_str),
+            Some("SELECT campaign.id FROM campaign")
+        );
+        assert_eq!(
+            table.get("keywords").and_then(Value::as_str),
+            Some("SELECT ad_group_criterion.keyword.text\nFROM keyword_view\n")
+        );
+        assert!(!table.contains_key("broken"));
+
+        let names: Vec<&str> = malformed.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["broken"]);
+    }
+
+    #[test]
+    fn parse_query_entries_valid_file() {
+        let (table, malformed) = parse_query_entries("a = \"SELECT customer.id FROM customer\"\nb = 'SELECT campaign.id FROM campaign'\n");
+
+        assert_eq!(table.len(), 2);
+        assert!(malformed.is_empty());
+    }
+
+    #[test]
+    fn split_query_entries_by_name() {
+        let entries = split_query_entries(MIXED_QUERIES);
+
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["campaigns", "broken", "keywords"]);
+        assert!(entries[2].1.ends_with("FROM keyword_view\n\"\"\"\n"));
+    }
+
+    #[tokio::test]
+    async fn get_query_from_file_names_malformed_entry() {
+        let path = env::temp_dir().join(format!("mcc-gaql-{}-queries.toml", std::process::id()));
+        fs::write(&path, MIXED_QUERIES).unwrap();
+
+        let valid = get_query_from_file(&path, "campaigns").await;
+        let malformed = get_query_from_file(&path, "broken").await;
+        let missing = get_query_from_file(&path, "missing").await;
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(valid.unwrap(), "SELECT campaign.id FROM campaign");
+        assert!(malformed.unwrap_err().to_string().contains("Unable to parse stored query 'broken'"));
+        assert_eq!(missing.unwrap_err().to_string(), "Query not found: missing");
+    }
+
+    #[test]
+    fn parse_relative_date_forms() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+
+        assert_eq!(parse_relative_date("today", today).unwrap(), today);
+        assert_eq!(parse_relative_date(" Yesterday ", today).unwrap(), date(2024, 3, 30));
+        assert_eq!(parse_relative_date("30d", today).unwrap(), date(2024, 3, 1));
+        assert_eq!(parse_relative_date("2w", today).unwrap(), date(2024, 3, 17));
+        // month arithmetic clamps to the end of shorter months
+        assert_eq!(parse_relative_date("1m", today).unwrap(), date(2024, 2, 29));
+        assert_eq!(parse_relative_date("1y", today).unwrap(), date(2023, 3, 31));
+        assert_eq!(parse_relative_date("-30D", today).unwrap(), date(2024, 3, 1));
+        assert_eq!(parse_relative_date("2023-12-25", today).unwrap(), date(2023, 12, 25));
+    }
+
+    #[test]
+    fn parse_relative_date_invalid() {
+        let today = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+
+        let err = parse_relative_date("3q", today).unwrap_err().to_string();
+        assert!(err.contains("unknown unit 'q'"), "{err}");
+        let err = parse_relative_date("400000y", today).unwrap_err().to_string();
+        assert!(err.contains("out of range"), "{err}");
+        assert!(parse_relative_date("xd", today).is_err());
+        assert!(parse_relative_date("2024-13-01", today).is_err());
+    }
+
+    #[test]
+    fn transcode_replaces_unmappable_characters() {
+        let (encoded, replaced) = transcode("Café 日本 →", encoding_rs::WINDOWS_1252, b"?");
+
+        assert_eq!(encoded, b"Caf\xe9 ?? ?");
+        assert_eq!(replaced, 3);
+    }
+
+    #[test]
+    fn transcode_output_larger_than_buffer() {
+        let text = "é".repeat(10_000);
+
+        let (encoded, replaced) = transcode(&text, encoding_rs::WINDOWS_1252, b"?");
+
+        assert_eq!(encoded, vec![0xe9; 10_000]);
+        assert_eq!(replaced, 0);
+    }
+
+    #[tokio::test]
+    async fn dedup_account_ids_across_sources() {
+        let path = env::temp_dir().join(format!("mcc-gaql-{}-customerids.txt", std::process::id()));
+        fs::write(&path, "1111111111\n2222222222\n1111111111\n").unwrap();
+
+        let mut customer_ids = get_child_account_ids_from_file(&path).await.unwrap();
+        fs::remove_file(&path).unwrap();
+        // e.g. accounts discovered under an MCC, overlapping the file
+        customer_ids.extend(["3333333333", "2222222222", "4444444444"].map(str::to_owned));
+
+        let duplicates = dedup_account_ids(&mut customer_ids);
+
+        assert_eq!(customer_ids, ["1111111111", "2222222222", "3333333333", "4444444444"]);
+        assert_eq!(duplicates, ["1111111111", "2222222222"]);
+    }
+
+    #[test]
+    fn account_pairs_keep_first_mcc() {
+        let path = env::temp_dir().join(format!("mcc-gaql-{}-pairs.csv", std::process::id()));
+        fs::write(
+            &path,
+            "mcc_id,customer_id\n999-999-9999,111-111-1111\n8888888888,2222222222\n9999999999,1111111111\n",
+        )
+        .unwrap();
+        let pairs = get_account_pairs_from_file(&path);
+
+        fs::write(&path, "9999999999,1111111111\n8888888888,1111111111\n").unwrap();
+        let conflicting = get_account_pairs_from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            pairs.unwrap(),
+            [
+                ("9999999999".to_owned(), "1111111111".to_owned()),
+                ("8888888888".to_owned(), "2222222222".to_owned()),
+            ]
+        );
+        let err = conflicting.unwrap_err().to_string();
+        assert!(err.contains("paired with both MCC 9999999999 and 8888888888"), "{err}");
+    }
+
+    #[test]
+    fn rename_map_from_csv_and_toml() {
+        let csv_path = env::temp_dir().join(format!("mcc-gaql-{}-renames.csv", std::process::id()));
+        let toml_path = csv_path.with_extension("toml");
+        fs::write(&csv_path, "from,to\n\"metrics.clicks\",Clicks\n\ncampaign.name, Campaign\n").unwrap();
+        fs::write(&toml_path, "\"metrics.clicks\" = \"Clicks\"\n\"campaign.name\" = \"Campaign\"\n").unwrap();
+
+        let from_csv = get_rename_map_from_file(&csv_path);
+        let from_toml = get_rename_map_from_file(&toml_path);
+        fs::remove_file(&csv_path).unwrap();
+        fs::remove_file(&toml_path).unwrap();
+
+        let expected = [
+            ("metrics.clicks".to_owned(), "Clicks".to_owned()),
+            ("campaign.name".to_owned(), "Campaign".to_owned()),
+        ];
+        assert_eq!(from_csv.unwrap(), expected);
+        let mut from_toml = from_toml.unwrap();
+        from_toml.sort();
+        assert_eq!(from_toml, [expected[1].clone(), expected[0].clone()]);
+    }
+
+    #[test]
+    fn query_fingerprint_is_stable() {
+        let queries = vec!["SELECT campaign.id FROM campaign".to_owned()];
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let fingerprint = query_fingerprint(&queries, &ids(&["1111111111", "2222222222"]), &HashMap::new());
+
+        assert_eq!(fingerprint.len(), 16);
+        assert_eq!(
+            query_fingerprint(&queries, &ids(&["2222222222", "1111111111", "2222222222"]), &HashMap::new()),
+            fingerprint
+        );
+    }
+
+    #[test]
+    fn query_fingerprint_changes_with_inputs() {
+        let queries = vec!["SELECT campaign.id FROM campaign".to_owned()];
+        let ids = vec!["1111111111".to_owned(), "2222222222".to_owned()];
+        let fingerprint = query_fingerprint(&queries, &ids, &HashMap::new());
+
+        let other_queries = vec!["SELECT campaign.id FROM campaign LIMIT 1".to_owned()];
+        assert_ne!(query_fingerprint(&other_queries, &ids, &HashMap::new()), fingerprint);
+        assert_ne!(query_fingerprint(&queries, &ids[..1], &HashMap::new()), fingerprint);
+
+        let query_map = HashMap::from([("2222222222".to_owned(), other_queries[0].clone())]);
+        assert_ne!(query_fingerprint(&queries, &ids, &query_map), fingerprint);
+        // mapped accounts not queried don't count
+        let query_map = HashMap::from([("3333333333".to_owned(), other_queries[0].clone())]);
+        assert_eq!(query_fingerprint(&queries, &ids, &query_map), fingerprint);
+    }
+
+    #[test]
+    fn apply_query_params_expands_lists() {
+        let query = "SELECT campaign.id FROM campaign \
+                     WHERE campaign.id IN ({{ids}}) AND campaign.status in ( {{statuses}} ) LIMIT {{limit}}";
+        let params = [
+            "ids=1, 2,3".to_owned(),
+            "statuses=ENABLED,PAUSED".to_owned(),
+            "limit=10".to_owned(),
+        ];
+
+        assert_eq!(
+            apply_query_params(query, &params).unwrap(),
+            "SELECT campaign.id FROM campaign \
+             WHERE campaign.id IN (1, 2, 3) AND campaign.status in ( 'ENABLED', 'PAUSED' ) LIMIT 10"
+        );
+        assert!(apply_query_params(query, &["ids=1,,2".to_owned(), "statuses=x".to_owned(), "limit=1".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn apply_query_params_escapes_quotes() {
+        let query = "SELECT campaign.id FROM campaign WHERE campaign.name = '{{name}}' AND campaign.name IN ({{names}})";
+        let params = [r"name=Bob's \ brand".to_owned(), "names=it's,\"x\"".to_owned()];
+
+        assert_eq!(
+            apply_query_params(query, &params).unwrap(),
+            r#"SELECT campaign.id FROM campaign WHERE campaign.name = 'Bob\'s \\ brand' AND campaign.name IN ('it\'s', '\"x\"')"#
+        );
+    }
+
+    #[test]
+    fn apply_query_params_invalid() {
+        let query = "SELECT campaign.id FROM campaign WHERE campaign.name = '{{name}}'";
+
+        let err = apply_query_params(query, &[]).unwrap_err().to_string();
+        assert_eq!(err, "Missing --param for query placeholder: name");
+        let err = apply_query_params("SELECT campaign.id FROM campaign LIMIT {{limit", &["limit=1".to_owned()])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Unterminated placeholder in query: {{limit");
+        assert!(apply_query_params(query, &["name".to_owned()]).is_err());
+        assert!(apply_query_params(query, &["bad-name=x".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn apply_query_params_deferring_keeps_placeholder() {
+        let query = "SELECT campaign.id FROM campaign WHERE campaign.id IN ({{ids}}) LIMIT {{limit}}";
+
+        assert_eq!(
+            apply_query_params_deferring(query, &["limit=5".to_owned()], "ids").unwrap(),
+            "SELECT campaign.id FROM campaign WHERE campaign.id IN ({{ids}}) LIMIT 5"
+        );
+    }
+}